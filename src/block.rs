@@ -0,0 +1,353 @@
+use crate::{
+    config::{Invalid, InvalidType, Parser},
+    matcher::Matcher,
+};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr},
+    path::{Path, PathBuf},
+    result,
+    str::FromStr,
+};
+use tokio::{fs, io::Result};
+use updns::DnsRecord;
+
+// Names found in every hosts file, they are not blocked
+const LOCAL_NAMES: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+// The answer to a blocked query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockMode {
+    NxDomain,
+    Refused,
+    // 0.0.0.0 or ::, other types get no records
+    Null,
+    NoData,
+}
+
+impl FromStr for BlockMode {
+    type Err = ();
+
+    fn from_str(text: &str) -> result::Result<BlockMode, ()> {
+        match text.to_ascii_lowercase().as_str() {
+            "nxdomain" => Ok(BlockMode::NxDomain),
+            "refused" => Ok(BlockMode::Refused),
+            "null" => Ok(BlockMode::Null),
+            "nodata" => Ok(BlockMode::NoData),
+            _ => Err(()),
+        }
+    }
+}
+
+// The answer to a rejected client or upstream answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectMode {
+    Refuse,
+    // No answer at all
+    Drop,
+}
+
+impl FromStr for RejectMode {
+    type Err = ();
+
+    fn from_str(text: &str) -> result::Result<RejectMode, ()> {
+        match text.to_ascii_lowercase().as_str() {
+            "refuse" => Ok(RejectMode::Refuse),
+            "drop" => Ok(RejectMode::Drop),
+            _ => Err(()),
+        }
+    }
+}
+
+// RFC 1918, loopback, link-local and unique local addresses
+pub fn is_private(ip: &IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            v4.is_private() || v4.is_loopback() || v4.is_link_local() || v4.is_unspecified()
+        }
+        IpAddr::V6(v6) => {
+            let segment = v6.segments()[0];
+            v6.is_loopback()
+                || v6 == Ipv6Addr::UNSPECIFIED
+                // fe80::/10
+                || segment & 0xffc0 == 0xfe80
+                // fc00::/7
+                || segment & 0xfe00 == 0xfc00
+        }
+    }
+}
+
+// The first private address in the answer section
+pub fn private_address(answers: &[DnsRecord]) -> Option<IpAddr> {
+    answers
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        })
+        .find(is_private)
+}
+
+// Formats of imported blocklists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // 0.0.0.0 example.com
+    Hosts,
+    // example.com
+    Domains,
+    // ||example.com^  or  @@||example.com^
+    Adblock,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(text: &str) -> result::Result<Format, ()> {
+        match text.to_ascii_lowercase().as_str() {
+            "hosts" => Ok(Format::Hosts),
+            "domains" => Ok(Format::Domains),
+            "adblock" => Ok(Format::Adblock),
+            _ => Err(()),
+        }
+    }
+}
+
+// Plain text domains are looked up directly, there can be many of them
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+    // The domain and its subdomains
+    suffixes: HashSet<String>,
+    patterns: Vec<Matcher>,
+    // Never blocked, with their subdomains
+    exceptions: HashSet<String>,
+}
+
+// example.com, then its parents
+// ads.example.com  ->  [ads.example.com, example.com, com]
+fn parents(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |name| {
+        name.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+// A domain name without wildcards or paths
+fn domain(text: &str) -> Option<String> {
+    let valid = text
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return None;
+    }
+    Parser::hostname(text).ok()
+}
+
+impl Blocklist {
+    pub fn new() -> Blocklist {
+        Blocklist::default()
+    }
+
+    pub fn push(&mut self, matcher: Matcher) {
+        match matcher.as_static() {
+            Some(domain) => {
+                self.domains.insert(domain.to_string());
+            }
+            None => self.patterns.push(matcher),
+        }
+    }
+
+    pub fn extend(&mut self, other: Blocklist) {
+        self.domains.extend(other.domains);
+        self.suffixes.extend(other.suffixes);
+        self.patterns.extend(other.patterns);
+        self.exceptions.extend(other.exceptions);
+    }
+
+    // The rule blocking the domain
+    pub fn find(&self, domain: &str) -> Option<String> {
+        if parents(domain).any(|name| self.exceptions.contains(name)) {
+            return None;
+        }
+        if self.domains.contains(domain) {
+            return Some(domain.to_string());
+        }
+        if let Some(name) = parents(domain).find(|name| self.suffixes.contains(*name)) {
+            return Some(format!("||{}^", name));
+        }
+        self.patterns
+            .iter()
+            .find(|matcher| matcher.is_match(domain))
+            .map(|matcher| matcher.to_string())
+    }
+}
+
+pub async fn load<P: AsRef<Path>>(format: Format, path: P) -> Result<(Blocklist, Vec<Invalid>)> {
+    let content = fs::read_to_string(&path).await?;
+    let (blocklist, mut invalid) = parse(format, &content);
+    for invalid in invalid.iter_mut() {
+        invalid.path = path.as_ref().to_path_buf();
+    }
+    Ok((blocklist, invalid))
+}
+
+pub fn parse(format: Format, content: &str) -> (Blocklist, Vec<Invalid>) {
+    let mut blocklist = Blocklist::new();
+    let mut invalid = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        let mut error = |kind| {
+            invalid.push(Invalid {
+                path: PathBuf::new(),
+                line: i + 1,
+                source: line.to_string(),
+                kind,
+            })
+        };
+
+        match format {
+            Format::Hosts | Format::Domains => {
+                let text = line.split('#').next().unwrap_or_default();
+                let mut fields = text.split_ascii_whitespace();
+                if format == Format::Hosts {
+                    match fields.next().map(|ip| ip.parse::<IpAddr>()) {
+                        Some(Ok(_)) => {}
+                        Some(Err(_)) => {
+                            error(InvalidType::IpAddr);
+                            continue;
+                        }
+                        None => continue,
+                    }
+                }
+                for name in fields {
+                    match domain(name) {
+                        Some(name) if LOCAL_NAMES.contains(&name.as_str()) => {}
+                        Some(name) => {
+                            blocklist.domains.insert(name);
+                        }
+                        None => error(InvalidType::Hostname),
+                    }
+                }
+            }
+            // Only domain rules apply to DNS, comments, element hiding
+            // and rules with options or paths are skipped
+            Format::Adblock => {
+                let (exception, rule) = match line.strip_prefix("@@") {
+                    Some(rule) => (true, rule),
+                    None => (false, line),
+                };
+                let name = rule
+                    .strip_prefix("||")
+                    .and_then(|rule| rule.strip_suffix('^'))
+                    .and_then(domain);
+                match name {
+                    Some(name) if exception => {
+                        blocklist.exceptions.insert(name);
+                    }
+                    Some(name) => {
+                        blocklist.suffixes.insert(name);
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    (blocklist, invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find() {
+        let mut blocklist = Blocklist::new();
+        for rule in ["ads.example.com", "*.doubleclick.net", r"~(^|\.)ads\."] {
+            blocklist.push(Matcher::new(rule).unwrap());
+        }
+
+        assert_eq!(
+            blocklist.find("ads.example.com"),
+            Some("ads.example.com".to_string())
+        );
+        assert_eq!(
+            blocklist.find("ad.doubleclick.net"),
+            Some("*.doubleclick.net".to_string())
+        );
+        assert_eq!(
+            blocklist.find("cdn.ads.example.org"),
+            Some(r"~(^|\.)ads\.".to_string())
+        );
+        assert_eq!(blocklist.find("doubleclick.net"), None);
+        assert_eq!(blocklist.find("example.com"), None);
+
+        assert_eq!("NODATA".parse(), Ok(BlockMode::NoData));
+        assert!("drop".parse::<BlockMode>().is_err());
+    }
+
+    #[test]
+    fn private() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.0.1",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:192.168.0.1",
+        ] {
+            assert!(is_private(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "2001:db8::1", "fec0::1"] {
+            assert!(!is_private(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn parse_formats() {
+        let (hosts, invalid) = parse(
+            Format::Hosts,
+            "# comment\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.com # inline\nads.example.org",
+        );
+        assert_eq!(invalid.len(), 1);
+        assert!(hosts.find("localhost").is_none());
+        assert!(hosts.find("tracker.example.com").is_some());
+
+        let (domains, invalid) = parse(Format::Domains, "ads.example.com\n\nbad..name\n");
+        assert_eq!(invalid.len(), 1);
+        assert!(domains.find("ads.example.com").is_some());
+        assert!(domains.find("cdn.ads.example.com").is_none());
+
+        let (mut adblock, invalid) = parse(
+            Format::Adblock,
+            "[Adblock Plus 2.0]\n! comment\n||example.com^\n@@||good.example.com^\n##.banner\n||ads.example.org^$third-party\n/ads/*",
+        );
+        assert!(invalid.is_empty());
+        assert_eq!(
+            adblock.find("example.com"),
+            Some("||example.com^".to_string())
+        );
+        assert_eq!(
+            adblock.find("cdn.ads.example.com"),
+            Some("||example.com^".to_string())
+        );
+        assert_eq!(adblock.find("api.good.example.com"), None);
+        assert_eq!(adblock.find("ads.example.org"), None);
+
+        // Exceptions apply to the other rules as well
+        adblock.extend(domains);
+        adblock.push(Matcher::new("*.example.com").unwrap());
+        assert_eq!(adblock.find("good.example.com"), None);
+    }
+}
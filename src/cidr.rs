@@ -0,0 +1,147 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+// An address prefix: 192.168.0.0/16  or  fd00::/8
+// A single address is a prefix of full length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(text: &str) -> Option<Cidr> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (text.parse().ok()?, None),
+        };
+        let prefix = prefix.unwrap_or(Self::max_prefix(&addr));
+        Self::from_addr(addr, prefix)
+    }
+
+    // The network of an address
+    pub fn from_addr(addr: IpAddr, prefix: u8) -> Option<Cidr> {
+        if prefix > Self::max_prefix(&addr) {
+            return None;
+        }
+        Some(Cidr {
+            addr: Self::mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    fn max_prefix(addr: &IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    // Clear the host bits
+    fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+        match addr {
+            IpAddr::V4(v4) => {
+                let bits = u32::from(v4) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(bits))
+            }
+            IpAddr::V6(v6) => {
+                let bits = u128::from(v6) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(bits))
+            }
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // Clients on a dual-stack socket show up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                Self::mask(ip, self.prefix) == self.addr
+            }
+            _ => false,
+        }
+    }
+}
+
+// Client rules, the first matching rule decides and unmatched clients are allowed
+// allow 192.168.0.0/16  deny 0.0.0.0/0
+#[derive(Debug, Default)]
+pub struct Acl(Vec<(bool, Cidr)>);
+
+impl Acl {
+    pub fn new() -> Acl {
+        Acl::default()
+    }
+
+    pub fn push(&mut self, allow: bool, cidr: Cidr) {
+        self.0.push((allow, cidr));
+    }
+
+    pub fn extend(&mut self, other: Acl) {
+        self.0.extend(other.0);
+    }
+
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        self.0
+            .iter()
+            .find(|(_, cidr)| cidr.contains(ip))
+            .is_none_or(|(allow, _)| *allow)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains() {
+        let cidr = Cidr::new("192.168.1.20/16").unwrap();
+        assert_eq!(cidr.to_string(), "192.168.0.0/16");
+        assert!(cidr.contains(&"192.168.255.1".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:192.168.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"192.169.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let cidr = Cidr::new("0.0.0.0/0").unwrap();
+        assert!(cidr.contains(&"8.8.8.8".parse().unwrap()));
+
+        let cidr = Cidr::new("fd00::/8").unwrap();
+        assert!(cidr.contains(&"fdab::1".parse().unwrap()));
+        assert!(!cidr.contains(&"fe80::1".parse().unwrap()));
+
+        let cidr = Cidr::new("10.0.0.5").unwrap();
+        assert!(cidr.contains(&"10.0.0.5".parse().unwrap()));
+        assert!(!cidr.contains(&"10.0.0.6".parse().unwrap()));
+
+        assert!(Cidr::new("10.0.0.0/33").is_none());
+        assert!(Cidr::new("example.com").is_none());
+    }
+
+    #[test]
+    fn acl() {
+        let mut acl = Acl::new();
+        assert!(acl.allows(&"8.8.8.8".parse().unwrap()));
+
+        acl.push(true, Cidr::new("192.168.0.0/16").unwrap());
+        acl.push(false, Cidr::new("0.0.0.0/0").unwrap());
+        assert!(acl.allows(&"192.168.1.1".parse().unwrap()));
+        assert!(!acl.allows(&"8.8.8.8".parse().unwrap()));
+        assert!(acl.allows(&"::1".parse().unwrap()));
+    }
+}
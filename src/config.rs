@@ -1,8 +1,25 @@
-use crate::matcher::Matcher;
+use crate::{
+    block::{self, BlockMode, Blocklist, RejectMode},
+    cidr::{Acl, Cidr},
+    dns64::Dns64,
+    ecs::Ecs,
+    matcher::Matcher,
+    ratelimit::Limit,
+    rewrite::Mapping,
+    rpz::Rpz,
+    secondary::Secondary,
+    tsig::Key,
+    view::{self, View},
+    zone::{self, Zone},
+    zonefile, DEFAULT_TTL,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use futures_util::future::{BoxFuture, FutureExt};
 use logs::error;
 use std::{
-    net::{IpAddr, SocketAddr},
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
     result,
     slice::Iter,
@@ -13,6 +30,7 @@ use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt, Result},
 };
+use updns::{DnsRecord, QueryType};
 
 // Parse time format into Duration
 pub fn try_parse_duration(text: &str) -> result::Result<Duration, ()> {
@@ -45,6 +63,8 @@ pub fn try_parse_duration(text: &str) -> result::Result<Duration, ()> {
 
 #[derive(Debug)]
 pub struct Invalid {
+    // The config file, or an imported blocklist or zone file
+    pub path: PathBuf,
     pub line: usize,
     pub source: String,
     pub kind: InvalidType,
@@ -58,7 +78,8 @@ impl MultipleInvalid for Vec<Invalid> {
     fn print(&self) {
         for invalid in self {
             error!(
-                "[line:{}] {} `{}`",
+                "[{}:{}] {} `{}`",
+                invalid.path.display(),
                 invalid.line,
                 invalid.kind.description(),
                 invalid.source
@@ -72,7 +93,23 @@ pub enum InvalidType {
     Regex,
     SocketAddr,
     IpAddr,
+    Hostname,
+    Cidr,
+    Mapping,
+    Record,
+    Zone,
+    Key,
+    BlockMode,
+    BlockFormat,
+    RejectMode,
     Timeout,
+    UdpSize,
+    Ecs,
+    Dns64,
+    QueryType,
+    Limit,
+    Ttl,
+    Bind,
     Other,
 }
 
@@ -81,42 +118,287 @@ impl InvalidType {
         match self {
             InvalidType::SocketAddr => "Cannot parse socket address",
             InvalidType::IpAddr => "Cannot parse ip address",
+            InvalidType::Hostname => "Cannot parse hostname",
+            InvalidType::Cidr => "Cannot parse address prefix",
+            InvalidType::Mapping => "Mapped prefixes must have the same family and length",
+            InvalidType::Record => "Cannot parse record data",
+            InvalidType::Zone => "Zone file has no SOA record",
+            InvalidType::Key => "Cannot parse base64 key secret",
+            InvalidType::BlockMode => "Block mode is one of nxdomain, refused, null, nodata",
+            InvalidType::RejectMode => "Expected refuse or drop",
+            InvalidType::BlockFormat => "Blocklist format is one of hosts, domains, adblock",
             InvalidType::Regex => "Cannot parse regular expression",
             InvalidType::Timeout => "Cannot parse timeout",
+            InvalidType::UdpSize => "UDP size is a number from 512 to 65535",
+            InvalidType::Ecs => "Expected strip or the IPv4 and IPv6 prefix lengths",
+            InvalidType::Dns64 => {
+                "DNS64 prefix is an IPv6 prefix of length 32, 40, 48, 56, 64 or 96"
+            }
+            InvalidType::QueryType => "Cannot parse query type",
+            InvalidType::Limit => "Cannot parse rate limit, a rate per second and a window",
+            InvalidType::Ttl => "Cannot parse TTL",
+            InvalidType::Bind => "Address is taken by a wildcard bind address on the same port",
             InvalidType::Other => "Invalid line",
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub enum Record {
+    Ip(IpAddr),
+    // example.com -> example.net
+    Cname(String),
+    // example.com ~> example.net
+    Alias(String),
+    // example.com MX 10 mail.example.com
+    // The domain of the record is replaced by the queried name
+    Data(DnsRecord),
+}
+
+impl From<DnsRecord> for Record {
+    fn from(record: DnsRecord) -> Record {
+        match record {
+            DnsRecord::A { addr, .. } => Record::Ip(IpAddr::V4(addr)),
+            DnsRecord::AAAA { addr, .. } => Record::Ip(IpAddr::V6(addr)),
+            DnsRecord::CNAME { host, .. } => Record::Cname(host),
+            data => Record::Data(data),
+        }
+    }
+}
+
+impl Record {
+    // The record answered for the domain, an alias has no record of its own
+    pub fn to_record(&self, domain: String) -> Option<DnsRecord> {
+        let record = match self {
+            Record::Ip(IpAddr::V4(addr)) => DnsRecord::A {
+                domain,
+                addr: *addr,
+                ttl: DEFAULT_TTL,
+            },
+            Record::Ip(IpAddr::V6(addr)) => DnsRecord::AAAA {
+                domain,
+                addr: *addr,
+                ttl: DEFAULT_TTL,
+            },
+            Record::Cname(host) => DnsRecord::CNAME {
+                domain,
+                host: host.clone(),
+                ttl: DEFAULT_TTL,
+            },
+            Record::Data(data) => {
+                let mut data = data.clone();
+                data.set_domain(domain);
+                data
+            }
+            Record::Alias(_) => return None,
+        };
+        Some(record)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Record::Ip(ip) => write!(f, "{}", ip),
+            Record::Cname(host) => write!(f, "-> {}", host),
+            Record::Alias(host) => write!(f, "~> {}", host),
+            Record::Data(record) => match record {
+                DnsRecord::A { addr, .. } => write!(f, "A {}", addr),
+                DnsRecord::AAAA { addr, .. } => write!(f, "AAAA {}", addr),
+                DnsRecord::CNAME { host, .. } => write!(f, "CNAME {}", host),
+                DnsRecord::SOA {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ..
+                } => write!(
+                    f,
+                    "SOA {} {} {} {} {} {} {}",
+                    mname, rname, serial, refresh, retry, expire, minimum
+                ),
+                DnsRecord::MX { priority, host, .. } => write!(f, "MX {} {}", priority, host),
+                DnsRecord::TXT { data, .. } => {
+                    write!(f, "TXT")?;
+                    for text in data {
+                        write!(f, " {}", quote(text))?;
+                    }
+                    Ok(())
+                }
+                DnsRecord::SRV {
+                    priority,
+                    weight,
+                    port,
+                    host,
+                    ..
+                } => write!(f, "SRV {} {} {} {}", priority, weight, port, host),
+                DnsRecord::CAA {
+                    flags, tag, value, ..
+                } => write!(f, "CAA {} {} {}", flags, tag, quote(value)),
+                DnsRecord::NS { host, .. } => write!(f, "NS {}", host),
+                DnsRecord::PTR { host, .. } => write!(f, "PTR {}", host),
+                // Not a type of the config file, updates with it are refused
+                _ => write!(f, "{:?}", record),
+            },
+        }
+    }
+}
+
+// Quoted as read by `Parser::fields`
+// say "hi"  ->  "say \"hi\""
+fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for ch in text.chars() {
+        if ch == '"' || ch == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 4.3.2.1.in-addr.arpa -> 1.2.3.4
+// b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa -> 4321:0:1:2:3:4:567:89ab
+pub fn parse_arpa(name: &str) -> Option<IpAddr> {
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = rest
+            .split('.')
+            .map(|n| n.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )));
+    }
+
+    if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let nibbles = rest
+            .split('.')
+            .map(|n| match n.len() {
+                1 => u8::from_str_radix(n, 16).ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut octets = [0; 16];
+        for (i, pair) in nibbles.rchunks(2).enumerate() {
+            octets[i] = (pair[1] << 4) | pair[0];
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+    }
+
+    None
+}
+
+#[derive(Debug, Clone)]
 pub struct Hosts {
-    record: Vec<(Matcher, IpAddr)>,
+    record: Vec<(Matcher, Record)>,
+    // Plain text domains indexed by address, used for PTR queries
+    reverse: HashMap<IpAddr, String>,
 }
 
 impl Hosts {
     pub fn new() -> Hosts {
-        Hosts { record: Vec::new() }
+        Hosts {
+            record: Vec::new(),
+            reverse: HashMap::new(),
+        }
     }
 
-    fn push(&mut self, record: (Matcher, IpAddr)) {
+    pub fn push(&mut self, record: (Matcher, Record)) {
+        if let (Some(domain), Record::Ip(ip)) = (record.0.as_static(), &record.1) {
+            self.reverse
+                .entry(*ip)
+                .or_insert_with(|| domain.to_string());
+        }
         self.record.push(record);
     }
 
     fn extend(&mut self, hosts: Hosts) {
+        for (ip, domain) in hosts.reverse {
+            self.reverse.entry(ip).or_insert(domain);
+        }
         self.record.extend(hosts.record);
     }
 
-    pub fn iter(&mut self) -> Iter<(Matcher, IpAddr)> {
+    pub fn retain<F: Fn(&Matcher, &Record) -> bool>(&mut self, f: F) {
+        let record = std::mem::take(&mut self.record);
+        self.reverse.clear();
+        for (matcher, record) in record {
+            if f(&matcher, &record) {
+                self.push((matcher, record));
+            }
+        }
+    }
+
+    // The domain configured for the address of a PTR query
+    pub fn reverse(&self, name: &str) -> Option<&str> {
+        parse_arpa(name)
+            .and_then(|ip| self.reverse.get(&ip))
+            .map(|domain| domain.as_str())
+    }
+
+    pub fn iter(&self) -> Iter<'_, (Matcher, Record)> {
         self.record.iter()
     }
 
-    pub fn get(&self, domain: &str) -> Option<&IpAddr> {
-        for (reg, ip) in &self.record {
-            if reg.is_match(domain) {
-                return Some(ip);
+    pub fn contains(&self, domain: &str) -> bool {
+        self.record.iter().any(|(reg, _)| reg.is_match(domain))
+    }
+
+    // The domain has records, or names below it have (an empty non-terminal)
+    // b.home.arpa exists when a.b.home.arpa or *.b.home.arpa is configured
+    pub fn exists(&self, domain: &str) -> bool {
+        self.record.iter().any(|(reg, _)| {
+            let name = reg.to_string();
+            reg.is_match(domain)
+                || (!name.starts_with('~')
+                    && zone::is_subdomain(name.trim_start_matches("*."), domain))
+        })
+    }
+
+    // The first matching CNAME or address wins, other records are collected
+    pub fn get(&self, domain: &str, qtype: QueryType) -> Vec<&Record> {
+        let mut records = Vec::new();
+        for (reg, record) in &self.record {
+            if !reg.is_match(domain) {
+                continue;
+            }
+            match record {
+                Record::Cname(_) | Record::Alias(_) => {
+                    if records.is_empty() {
+                        records.push(record);
+                        break;
+                    }
+                }
+                Record::Ip(ip) => {
+                    let found = match qtype {
+                        QueryType::A => ip.is_ipv4(),
+                        QueryType::AAAA => ip.is_ipv6(),
+                        _ => false,
+                    };
+                    if found && records.is_empty() {
+                        records.push(record);
+                        break;
+                    }
+                }
+                Record::Data(data) => {
+                    if data.qtype() == qtype {
+                        records.push(record);
+                    }
+                }
             }
         }
-        None
+        records
     }
 }
 
@@ -125,6 +407,47 @@ pub struct Config {
     pub bind: Vec<SocketAddr>,
     pub proxy: Vec<SocketAddr>,
     pub hosts: Hosts,
+    pub zones: Vec<Zone>,
+    pub allow_transfer: Vec<Cidr>,
+    pub allow_update: Vec<Cidr>,
+    // Zone and key name, requests signed with the key may transfer or update the zone
+    pub transfer_keys: Vec<(String, String)>,
+    pub update_keys: Vec<(String, String)>,
+    pub secondaries: Vec<Secondary>,
+    pub keys: Vec<Key>,
+    pub block: Blocklist,
+    // Proxied even when blocked or matched by the hosts
+    pub allow: Blocklist,
+    pub block_mode: Option<BlockMode>,
+    pub rpz: Rpz,
+    // Clients allowed to send queries
+    pub clients: Acl,
+    pub deny_mode: Option<RejectMode>,
+    pub rebind: Option<RejectMode>,
+    // Domains that may resolve to private addresses
+    pub rebind_allow: Blocklist,
+    pub maps: Vec<Mapping>,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub views: Vec<View>,
+    pub rate_limit: Option<Limit>,
+    pub rrl: Option<Limit>,
+    pub rrl_slip: Option<u32>,
+    // IPv4 and IPv6 prefix lengths of the networks limited by rate_limit
+    pub rate_limit_prefix: Option<(u8, u8)>,
+    pub max_udp_size: Option<u16>,
+    pub ecs: Option<Ecs>,
+    // Forwarding resolvers whose client subnet (ECS) may select a view
+    pub trusted_ecs: Vec<Cidr>,
+    pub dns64: Option<Dns64>,
+    // Added to the exclusions of DNS64 once the whole configuration is read
+    pub dns64_exclude: Vec<Cidr>,
+    pub dns64_clients: Vec<Cidr>,
+    // AAAA queries answered with NODATA when the name has an A record
+    pub filter_aaaa: Blocklist,
+    pub filter_aaaa_all: bool,
+    // Query types answered with NODATA
+    pub deny_types: Vec<QueryType>,
     pub timeout: Option<Duration>,
     pub invalid: Vec<Invalid>,
 }
@@ -133,9 +456,41 @@ impl Config {
     fn new() -> Config {
         Config {
             hosts: Hosts::new(),
+            zones: Vec::new(),
+            allow_transfer: Vec::new(),
+            allow_update: Vec::new(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            secondaries: Vec::new(),
+            keys: Vec::new(),
+            block: Blocklist::new(),
+            allow: Blocklist::new(),
+            block_mode: None,
+            rpz: Rpz::new(),
+            clients: Acl::new(),
+            deny_mode: None,
+            rebind: None,
+            rebind_allow: Blocklist::new(),
+            maps: Vec::new(),
+            min_ttl: None,
+            max_ttl: None,
             bind: Vec::new(),
             proxy: Vec::new(),
             invalid: Vec::new(),
+            views: Vec::new(),
+            rate_limit: None,
+            rrl: None,
+            rrl_slip: None,
+            rate_limit_prefix: None,
+            max_udp_size: None,
+            ecs: None,
+            trusted_ecs: Vec::new(),
+            dns64: None,
+            dns64_exclude: Vec::new(),
+            dns64_clients: Vec::new(),
+            filter_aaaa: Blocklist::new(),
+            filter_aaaa_all: false,
+            deny_types: Vec::new(),
             timeout: None,
         }
     }
@@ -144,6 +499,62 @@ impl Config {
         self.bind.extend(other.bind);
         self.proxy.extend(other.proxy);
         self.hosts.extend(other.hosts);
+        self.zones.extend(other.zones);
+        self.allow_transfer.extend(other.allow_transfer);
+        self.allow_update.extend(other.allow_update);
+        self.transfer_keys.extend(other.transfer_keys);
+        self.update_keys.extend(other.update_keys);
+        self.secondaries.extend(other.secondaries);
+        self.keys.extend(other.keys);
+        self.block.extend(other.block);
+        self.allow.extend(other.allow);
+        self.rpz.extend(other.rpz);
+        self.clients.extend(other.clients);
+        if other.deny_mode.is_some() {
+            self.deny_mode = other.deny_mode;
+        }
+        self.rebind_allow.extend(other.rebind_allow);
+        self.maps.extend(other.maps);
+        if other.min_ttl.is_some() {
+            self.min_ttl = other.min_ttl;
+        }
+        if other.max_ttl.is_some() {
+            self.max_ttl = other.max_ttl;
+        }
+        if other.rebind.is_some() {
+            self.rebind = other.rebind;
+        }
+        if other.block_mode.is_some() {
+            self.block_mode = other.block_mode;
+        }
+        self.views.extend(other.views);
+        if other.rate_limit.is_some() {
+            self.rate_limit = other.rate_limit;
+        }
+        if other.rrl.is_some() {
+            self.rrl = other.rrl;
+        }
+        if other.rrl_slip.is_some() {
+            self.rrl_slip = other.rrl_slip;
+        }
+        if other.rate_limit_prefix.is_some() {
+            self.rate_limit_prefix = other.rate_limit_prefix;
+        }
+        if other.max_udp_size.is_some() {
+            self.max_udp_size = other.max_udp_size;
+        }
+        if other.ecs.is_some() {
+            self.ecs = other.ecs;
+        }
+        self.trusted_ecs.extend(other.trusted_ecs);
+        if other.dns64.is_some() {
+            self.dns64 = other.dns64;
+        }
+        self.dns64_exclude.extend(other.dns64_exclude);
+        self.dns64_clients.extend(other.dns64_clients);
+        self.filter_aaaa.extend(other.filter_aaaa);
+        self.filter_aaaa_all |= other.filter_aaaa_all;
+        self.deny_types.extend(other.deny_types);
         self.invalid.extend(other.invalid);
         if other.timeout.is_some() {
             self.timeout = other.timeout;
@@ -176,50 +587,491 @@ impl Parser {
         })
     }
 
+    // Paths are relative to the config file
+    fn relative(&self, path: &str) -> PathBuf {
+        let mut path = PathBuf::from(path);
+        if path.is_relative() {
+            if let Some(parent) = self.path.parent() {
+                path = parent.join(path);
+            }
+        }
+        path
+    }
+
     async fn read_to_string(&mut self) -> Result<String> {
         let mut content = String::new();
         self.file.read_to_string(&mut content).await?;
         Ok(content)
     }
 
+    // The line ending of the file is kept, CRLF or LF
     pub async fn add(&mut self, domain: &str, ip: &str) -> Result<usize> {
-        if self.read_to_string().await?.ends_with('\n') {
+        let content = self.read_to_string().await?;
+        let newline = if content.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        if content.is_empty() || content.ends_with('\n') {
             self.file
                 .write(format!("{}  {}", domain, ip).as_bytes())
                 .await
         } else {
             self.file
-                .write(format!("\n{}  {}", domain, ip).as_bytes())
+                .write(format!("{}{}  {}", newline, domain, ip).as_bytes())
                 .await
         }
     }
 
+    // Remove the records matching the filter, other lines are kept as they are
+    pub async fn remove<F: Fn(&Matcher, &Record) -> bool>(&mut self, filter: F) -> Result<usize> {
+        let content = self.read_to_string().await?;
+        let mut removed = 0;
+        let mut lines = Vec::new();
+
+        // Records of views are not the hosts of the zones
+        let mut in_view = false;
+        // Lines keep their own ending
+        for line in content.split_inclusive('\n') {
+            let text = Self::strip(line);
+            if in_view || matches!(Self::split(text), Some(("view", value)) if value.ends_with('{'))
+            {
+                in_view = text != "}";
+                lines.push(line);
+                continue;
+            }
+            let record = Self::split(text).and_then(|(key, value)| Self::record(key, value).ok());
+            match record {
+                Some((matcher, record)) if filter(&matcher, &record) => removed += 1,
+                _ => lines.push(line),
+            }
+        }
+
+        if removed > 0 {
+            let mut kept = lines.concat();
+            // Without a final line ending when the removed last line had none
+            if !content.ends_with('\n') {
+                kept = kept.trim_end_matches(['\r', '\n']).to_string();
+            }
+            fs::write(&self.path, kept).await?;
+        }
+        Ok(removed)
+    }
+
+    // Remove the comment, a # inside quotes is kept
+    // example # ... -> example
+    fn strip(line: &str) -> &str {
+        let mut quoted = false;
+        let mut escaped = false;
+        for (pos, ch) in line.char_indices() {
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                '#' if !quoted => return line[0..pos].trim(),
+                _ => {}
+            }
+        }
+        line.trim()
+    }
+
+    // Split the line into the first word and the rest
+    // example.com -> example.net  ->  (example.com, -> example.net)
     fn split(text: &str) -> Option<(&str, &str)> {
-        let mut text = text.split_ascii_whitespace();
+        let (left, right) = text.split_once(|c: char| c.is_ascii_whitespace())?;
+        let right = right.trim();
+
+        if right.is_empty() {
+            return None;
+        }
+
+        Some((left, right))
+    }
 
-        if let (Some(left), Some(right)) = (text.next(), text.next()) {
-            if text.next().is_none() {
-                return Some((left, right));
+    // example.com  or  example.com.
+    pub fn hostname(text: &str) -> result::Result<String, InvalidType> {
+        let host = text.strip_suffix('.').unwrap_or(text);
+        if host.is_empty()
+            || host
+                .split('.')
+                .any(|label| label.is_empty() || label.len() > 63)
+        {
+            return Err(InvalidType::Hostname);
+        }
+        Ok(host.to_ascii_lowercase())
+    }
+
+    // Split on whitespace, quoted text is kept as one field
+    // TXT "v=spf1 -all"  ->  [TXT, v=spf1 -all]
+    pub fn fields(text: &str) -> result::Result<Vec<String>, InvalidType> {
+        let mut fields = Vec::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            if ch.is_ascii_whitespace() {
+                continue;
+            }
+            let mut field = String::new();
+            if ch == '"' {
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => field.push(chars.next().ok_or(InvalidType::Record)?),
+                        Some(c) => field.push(c),
+                        None => return Err(InvalidType::Record),
+                    }
+                }
+            } else {
+                field.push(ch);
+                while let Some(c) = chars.next_if(|c| !c.is_ascii_whitespace()) {
+                    field.push(c);
+                }
+            }
+            fields.push(field);
+        }
+
+        Ok(fields)
+    }
+
+    // HTTPS  or  TYPE65, also the types of record data
+    fn qtype(text: &str) -> Option<QueryType> {
+        let num = match text.to_ascii_uppercase().as_str() {
+            "A" => 1,
+            "NS" => 2,
+            "CNAME" => 5,
+            "SOA" => 6,
+            "PTR" => 12,
+            "HINFO" => 13,
+            "MX" => 15,
+            "TXT" => 16,
+            "AAAA" => 28,
+            "SRV" => 33,
+            "SVCB" => 64,
+            "HTTPS" => 65,
+            "ANY" => 255,
+            "CAA" => 257,
+            name => name.strip_prefix("TYPE")?.parse().ok()?,
+        };
+        Some(QueryType::from_num(num))
+    }
+
+    // IPv4 and IPv6 prefix lengths
+    // 24 56
+    fn prefixes(text: &str) -> Option<(u8, u8)> {
+        match text.split_ascii_whitespace().collect::<Vec<&str>>()[..] {
+            [v4, v6] => match (v4.parse().ok()?, v6.parse().ok()?) {
+                (v4, v6) if v4 <= 32 && v6 <= 128 => Some((v4, v6)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Time in seconds, units are allowed
+    // 3600  or  1h  or  1h30m
+    pub fn ttl(text: &str) -> Option<u32> {
+        if let Ok(n) = text.parse() {
+            return Some(n);
+        }
+
+        let mut total: u32 = 0;
+        let mut n: u32 = 0;
+        let mut digits = false;
+        for ch in text.chars() {
+            if let Some(d) = ch.to_digit(10) {
+                n = n.checked_mul(10)?.checked_add(d)?;
+                digits = true;
+                continue;
+            }
+            let unit = match ch.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                'w' => 7 * 24 * 60 * 60,
+                _ => return None,
+            };
+            if !digits {
+                return None;
+            }
+            total = total.checked_add(n.checked_mul(unit)?)?;
+            n = 0;
+            digits = false;
+        }
+
+        if digits {
+            return None;
+        }
+        Some(total)
+    }
+
+    // MX 10 mail.example.com
+    // Names in the data are resolved by `host`
+    pub fn data(
+        kind: &str,
+        fields: &[String],
+        host: &dyn Fn(&str) -> result::Result<String, InvalidType>,
+    ) -> result::Result<DnsRecord, InvalidType> {
+        macro_rules! parse {
+            ($i: expr) => {
+                fields
+                    .get($i)
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(InvalidType::Record)?
+            };
+        }
+        macro_rules! time {
+            ($i: expr) => {
+                fields
+                    .get($i)
+                    .and_then(|n| Self::ttl(n))
+                    .ok_or(InvalidType::Record)?
+            };
+        }
+        macro_rules! host {
+            ($i: expr) => {
+                host(fields.get($i).ok_or(InvalidType::Record)?)?
+            };
+        }
+        macro_rules! count {
+            ($n: expr) => {
+                if fields.len() != $n {
+                    return Err(InvalidType::Record);
+                }
+            };
+        }
+
+        let domain = String::new();
+        let ttl = DEFAULT_TTL;
+
+        let record = match Self::qtype(kind).ok_or(InvalidType::Record)? {
+            QueryType::A => {
+                count!(1);
+                DnsRecord::A {
+                    domain,
+                    addr: parse!(0),
+                    ttl,
+                }
+            }
+            QueryType::AAAA => {
+                count!(1);
+                DnsRecord::AAAA {
+                    domain,
+                    addr: parse!(0),
+                    ttl,
+                }
+            }
+            QueryType::NS => {
+                count!(1);
+                DnsRecord::NS {
+                    domain,
+                    host: host!(0),
+                    ttl,
+                }
+            }
+            QueryType::CNAME => {
+                count!(1);
+                DnsRecord::CNAME {
+                    domain,
+                    host: host!(0),
+                    ttl,
+                }
+            }
+            QueryType::PTR => {
+                count!(1);
+                DnsRecord::PTR {
+                    domain,
+                    host: host!(0),
+                    ttl,
+                }
+            }
+            QueryType::SOA => {
+                count!(7);
+                DnsRecord::SOA {
+                    domain,
+                    mname: host!(0),
+                    rname: host!(1),
+                    serial: parse!(2),
+                    refresh: time!(3),
+                    retry: time!(4),
+                    expire: time!(5),
+                    minimum: time!(6),
+                    ttl,
+                }
+            }
+            QueryType::MX => {
+                count!(2);
+                DnsRecord::MX {
+                    domain,
+                    priority: parse!(0),
+                    host: host!(1),
+                    ttl,
+                }
+            }
+            QueryType::TXT => {
+                if fields.is_empty() {
+                    return Err(InvalidType::Record);
+                }
+                // A single character-string is limited to 255 bytes
+                let mut data = Vec::new();
+                for field in fields {
+                    let mut text = field.as_str();
+                    while text.len() > 255 {
+                        let mut i = 255;
+                        while !text.is_char_boundary(i) {
+                            i -= 1;
+                        }
+                        let (left, right) = text.split_at(i);
+                        data.push(left.to_string());
+                        text = right;
+                    }
+                    data.push(text.to_string());
+                }
+                DnsRecord::TXT { domain, data, ttl }
+            }
+            QueryType::SRV => {
+                count!(4);
+                DnsRecord::SRV {
+                    domain,
+                    priority: parse!(0),
+                    weight: parse!(1),
+                    port: parse!(2),
+                    host: host!(3),
+                    ttl,
+                }
             }
+            QueryType::CAA => {
+                count!(3);
+                let tag = fields[1].to_ascii_lowercase();
+                if tag.is_empty() || tag.len() > 255 {
+                    return Err(InvalidType::Record);
+                }
+                DnsRecord::CAA {
+                    domain,
+                    flags: parse!(0),
+                    tag,
+                    value: fields[2].clone(),
+                    ttl,
+                }
+            }
+            _ => return Err(InvalidType::Record),
+        };
+
+        Ok(record)
+    }
+
+    // home.arpa  or  home.arpa ns.home.arpa
+    fn zone(text: &str) -> result::Result<Zone, InvalidType> {
+        let (name, nameserver) = match Self::split(text) {
+            Some((name, nameserver)) => (name, Some(Self::hostname(nameserver)?)),
+            None => (text, None),
+        };
+        Ok(Zone::new(Self::hostname(name)?, nameserver))
+    }
+
+    // example.com from 10.0.0.5:53  or  example.com from 10.0.0.5 key ddns.key
+    fn secondary(text: &str) -> result::Result<Secondary, InvalidType> {
+        let fields = text.split_ascii_whitespace().collect::<Vec<&str>>();
+        let key = match fields.len() {
+            3 => None,
+            5 if fields[3] == "key" => Some(Self::hostname(fields[4])?),
+            _ => return Err(InvalidType::Other),
+        };
+        if fields[1] != "from" {
+            return Err(InvalidType::Other);
+        }
+        let primary = match fields[2].parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => match fields[2].parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, 53),
+                Err(_) => return Err(InvalidType::SocketAddr),
+            },
+        };
+        Ok(Secondary::new(Self::hostname(fields[0])?, primary, key))
+    }
+
+    // home.arpa key ddns.key  ->  (home.arpa, ddns.key)
+    fn zone_key(text: &str) -> result::Result<(String, String), InvalidType> {
+        match text.split_ascii_whitespace().collect::<Vec<&str>>()[..] {
+            [zone, "key", key] => Ok((Self::hostname(zone)?, Self::hostname(key)?)),
+            _ => Err(InvalidType::Other),
         }
+    }
+
+    // Per second, with an optional window
+    // 20  or  20 5s
+    fn limit(text: &str, window: Duration) -> Option<Limit> {
+        let mut fields = text.split_ascii_whitespace();
+        let rate = fields
+            .next()?
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| *rate > 0.)?;
+        let window = match fields.next() {
+            Some(window) => try_parse_duration(window).ok()?,
+            None => window,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Limit { rate, window })
+    }
 
-        None
+    // 203.0.113.0/24 -> 10.1.0.0/24
+    fn mapping(text: &str) -> result::Result<Mapping, InvalidType> {
+        let (from, to) = text.split_once("->").ok_or(InvalidType::Other)?;
+        let from = Cidr::new(from.trim()).ok_or(InvalidType::Cidr)?;
+        let to = Cidr::new(to.trim()).ok_or(InvalidType::Cidr)?;
+        Mapping::new(from, to).ok_or(InvalidType::Mapping)
+    }
+
+    // ddns.key c2VjcmV0
+    fn key(text: &str) -> result::Result<Key, InvalidType> {
+        let (name, secret) = Self::split(text).ok_or(InvalidType::Other)?;
+        let secret = STANDARD.decode(secret).map_err(|_| InvalidType::Key)?;
+        Ok(Key::new(Self::hostname(name)?, secret))
     }
 
     // match host
     // example.com 0.0.0.0  or  0.0.0.0 example.com
-    fn record(left: &str, right: &str) -> result::Result<(Matcher, IpAddr), InvalidType> {
+    // example.com -> example.net  or  example.com ~> example.net
+    // example.com MX 10 mail.example.com
+    fn record(left: &str, right: &str) -> result::Result<(Matcher, Record), InvalidType> {
+        // domain -> target
+        if let Some(target) = right.strip_prefix("->") {
+            let target = Self::hostname(target.trim())?;
+            return Matcher::new(left)
+                .map(|host| (host, Record::Cname(target)))
+                .map_err(|_| InvalidType::Regex);
+        }
+
+        // domain ~> target
+        if let Some(target) = right.strip_prefix("~>") {
+            let target = Self::hostname(target.trim())?;
+            return Matcher::new(left)
+                .map(|host| (host, Record::Alias(target)))
+                .map_err(|_| InvalidType::Regex);
+        }
+
+        // domain TYPE data
+        if let Some((kind, data)) = Self::split(right) {
+            let record = Record::from(Self::data(kind, &Self::fields(data)?, &Self::hostname)?);
+            return Matcher::new(left)
+                .map(|host| (host, record))
+                .map_err(|_| InvalidType::Regex);
+        }
+
         // ip domain
         if let Ok(ip) = right.parse() {
             return Matcher::new(left)
-                .map(|host| (host, ip))
+                .map(|host| (host, Record::Ip(ip)))
                 .map_err(|_| InvalidType::Regex);
         }
 
         // domain ip
         if let Ok(ip) = left.parse() {
             return Matcher::new(right)
-                .map(|host| (host, ip))
+                .map(|host| (host, Record::Ip(ip)))
                 .map_err(|_| InvalidType::Regex);
         }
 
@@ -230,19 +1082,13 @@ impl Parser {
         async move {
             let content = self.read_to_string().await?;
             let mut config = Config::new();
+            // The view the lines belong to, until the closing brace
+            let mut view: Option<View> = None;
+            // Line, source and address of the bind lines, global and in views
+            let mut binds = Vec::new();
 
-            for (i, mut line) in content.lines().enumerate() {
-                if line.is_empty() {
-                    continue;
-                }
-                // remove comment
-                // example # ... -> example
-                if let Some(pos) = line.find('#') {
-                    line = &line[0..pos];
-                }
-
-                line = line.trim();
-
+            for (i, line) in content.lines().enumerate() {
+                let line = Self::strip(line);
                 if line.is_empty() {
                     continue;
                 }
@@ -250,6 +1096,7 @@ impl Parser {
                 macro_rules! invalid {
                     ($type: expr) => {{
                         config.invalid.push(Invalid {
+                            path: self.path.clone(),
                             line: i + 1,
                             source: line.to_string(),
                             kind: $type,
@@ -258,14 +1105,63 @@ impl Parser {
                     }};
                 }
 
+                if line == "}" {
+                    match view.take() {
+                        Some(view) => config.views.push(view),
+                        None => invalid!(InvalidType::Other),
+                    }
+                    continue;
+                }
+
                 let (key, value) = match Self::split(line) {
                     Some(d) => d,
                     None => invalid!(InvalidType::Other),
                 };
 
+                // Only addresses, upstreams, blocks and records are set per view
+                if let Some(view) = &mut view {
+                    match key {
+                        "source" => match Cidr::new(value) {
+                            Some(cidr) => view.sources.push(cidr),
+                            None => invalid!(InvalidType::Cidr),
+                        },
+                        "ecs" => match Cidr::new(value) {
+                            Some(cidr) => view.subnets.push(cidr),
+                            None => invalid!(InvalidType::Cidr),
+                        },
+                        "bind" => match value.parse::<SocketAddr>() {
+                            Ok(addr) => {
+                                binds.push((i, line.to_string(), addr));
+                                view.bind.push(addr)
+                            }
+                            Err(_) => invalid!(InvalidType::SocketAddr),
+                        },
+                        "proxy" => match value.parse::<SocketAddr>() {
+                            Ok(addr) => view.proxy.push(addr),
+                            Err(_) => invalid!(InvalidType::SocketAddr),
+                        },
+                        "block" => match Matcher::new(value) {
+                            Ok(matcher) => view.block.push(matcher),
+                            Err(_) => invalid!(InvalidType::Regex),
+                        },
+                        _ => match Self::record(key, value) {
+                            Ok(record) => view.hosts.push(record),
+                            Err(kind) => invalid!(kind),
+                        },
+                    }
+                    continue;
+                }
+
                 match key {
+                    "view" => match value.strip_suffix('{').map(str::trim) {
+                        Some(name) if !name.is_empty() => view = Some(View::new(name.to_string())),
+                        _ => invalid!(InvalidType::Other),
+                    },
                     "bind" => match value.parse::<SocketAddr>() {
-                        Ok(addr) => config.bind.push(addr),
+                        Ok(addr) => {
+                            binds.push((i, line.to_string(), addr));
+                            config.bind.push(addr)
+                        }
                         Err(_) => invalid!(InvalidType::SocketAddr),
                     },
                     "proxy" => match value.parse::<SocketAddr>() {
@@ -276,13 +1172,171 @@ impl Parser {
                         Ok(timeout) => config.timeout = Some(timeout),
                         Err(_) => invalid!(InvalidType::Timeout),
                     },
-                    "import" => {
-                        let mut path = PathBuf::from(value);
-                        if path.is_relative() {
-                            if let Some(parent) = self.path.parent() {
-                                path = parent.join(path);
-                            }
+                    "zone" => match Self::zone(value) {
+                        Ok(zone) => config.zones.push(zone),
+                        Err(kind) => invalid!(kind),
+                    },
+                    // A client address prefix, or a zone and the key of its requests
+                    "allow_transfer" => match (Cidr::new(value), Self::zone_key(value)) {
+                        (Some(cidr), _) => config.allow_transfer.push(cidr),
+                        (None, Ok(grant)) => config.transfer_keys.push(grant),
+                        (None, Err(_)) => invalid!(InvalidType::Cidr),
+                    },
+                    "allow_update" => match (Cidr::new(value), Self::zone_key(value)) {
+                        (Some(cidr), _) => config.allow_update.push(cidr),
+                        (None, Ok(grant)) => config.update_keys.push(grant),
+                        (None, Err(_)) => invalid!(InvalidType::Cidr),
+                    },
+                    "block" => match Matcher::new(value) {
+                        Ok(matcher) => config.block.push(matcher),
+                        Err(_) => invalid!(InvalidType::Regex),
+                    },
+                    // A client address prefix, or a domain
+                    "allow" => match (Cidr::new(value), Matcher::new(value)) {
+                        (Some(cidr), _) => config.clients.push(true, cidr),
+                        (None, Ok(matcher)) => config.allow.push(matcher),
+                        (None, Err(_)) => invalid!(InvalidType::Regex),
+                    },
+                    "deny" => match Cidr::new(value) {
+                        Some(cidr) => config.clients.push(false, cidr),
+                        None => invalid!(InvalidType::Cidr),
+                    },
+                    "deny_mode" => match value.parse() {
+                        Ok(mode) => config.deny_mode = Some(mode),
+                        Err(_) => invalid!(InvalidType::RejectMode),
+                    },
+                    "block_mode" => match value.parse() {
+                        Ok(mode) => config.block_mode = Some(mode),
+                        Err(_) => invalid!(InvalidType::BlockMode),
+                    },
+                    "rate_limit" => match Self::limit(value, Duration::from_secs(1)) {
+                        Some(limit) => config.rate_limit = Some(limit),
+                        None => invalid!(InvalidType::Limit),
+                    },
+                    "rrl" => match Self::limit(value, Duration::from_secs(15)) {
+                        Some(limit) => config.rrl = Some(limit),
+                        None => invalid!(InvalidType::Limit),
+                    },
+                    "rate_limit_prefix" => match Self::prefixes(value) {
+                        Some(prefixes) => config.rate_limit_prefix = Some(prefixes),
+                        None => invalid!(InvalidType::Limit),
+                    },
+                    "rrl_slip" => match value.parse() {
+                        Ok(slip) => config.rrl_slip = Some(slip),
+                        Err(_) => invalid!(InvalidType::Limit),
+                    },
+                    "max_udp_size" => match value.parse::<u16>() {
+                        Ok(size) if size >= 512 => config.max_udp_size = Some(size),
+                        _ => invalid!(InvalidType::UdpSize),
+                    },
+                    "ecs" => match value.parse() {
+                        Ok(ecs) => config.ecs = Some(ecs),
+                        Err(_) => invalid!(InvalidType::Ecs),
+                    },
+                    "trusted_ecs" => match Cidr::new(value) {
+                        Some(cidr) => config.trusted_ecs.push(cidr),
+                        None => invalid!(InvalidType::Cidr),
+                    },
+                    "dns64" => match Cidr::new(value).and_then(Dns64::new) {
+                        Some(dns64) => config.dns64 = Some(dns64),
+                        None => invalid!(InvalidType::Dns64),
+                    },
+                    "dns64_exclude" => match Cidr::new(value) {
+                        Some(cidr) => config.dns64_exclude.push(cidr),
+                        None => invalid!(InvalidType::Cidr),
+                    },
+                    "dns64_clients" => match Cidr::new(value) {
+                        Some(cidr) => config.dns64_clients.push(cidr),
+                        None => invalid!(InvalidType::Cidr),
+                    },
+                    // Every domain, or the matching ones
+                    "filter-aaaa" if value == "all" => config.filter_aaaa_all = true,
+                    "filter-aaaa" => match Matcher::new(value) {
+                        Ok(matcher) => config.filter_aaaa.push(matcher),
+                        Err(_) => invalid!(InvalidType::Regex),
+                    },
+                    "deny-type" => {
+                        match value
+                            .split_ascii_whitespace()
+                            .map(Self::qtype)
+                            .collect::<Option<Vec<QueryType>>>()
+                        {
+                            Some(types) => config.deny_types.extend(types),
+                            None => invalid!(InvalidType::QueryType),
+                        }
+                    }
+                    "min_ttl" => match Self::ttl(value) {
+                        Some(ttl) => config.min_ttl = Some(ttl),
+                        None => invalid!(InvalidType::Ttl),
+                    },
+                    "max_ttl" => match Self::ttl(value) {
+                        Some(ttl) => config.max_ttl = Some(ttl),
+                        None => invalid!(InvalidType::Ttl),
+                    },
+                    "map" => match Self::mapping(value) {
+                        Ok(mapping) => config.maps.push(mapping),
+                        Err(kind) => invalid!(kind),
+                    },
+                    "rebind_protection" => match value.parse() {
+                        Ok(mode) => config.rebind = Some(mode),
+                        Err(_) => invalid!(InvalidType::RejectMode),
+                    },
+                    "rebind_allow" => match Matcher::new(value) {
+                        Ok(matcher) => config.rebind_allow.push(matcher),
+                        Err(_) => invalid!(InvalidType::Regex),
+                    },
+                    "key" => match Self::key(value) {
+                        Ok(key) => config.keys.push(key),
+                        Err(kind) => invalid!(kind),
+                    },
+                    "secondary" => match Self::secondary(value) {
+                        Ok(secondary) => config.secondaries.push(secondary),
+                        Err(kind) => invalid!(kind),
+                    },
+                    "zonefile" => {
+                        let (name, path) = match Self::split(value) {
+                            Some(d) => d,
+                            None => invalid!(InvalidType::Other),
+                        };
+                        let name = match Self::hostname(name) {
+                            Ok(name) => name,
+                            Err(kind) => invalid!(kind),
+                        };
+                        let (records, invalid) = zonefile::load(&name, self.relative(path)).await?;
+                        config.invalid.extend(invalid);
+                        match Zone::from_records(name, records) {
+                            Some(zone) => config.zones.push(zone),
+                            None => invalid!(InvalidType::Zone),
                         }
+                    }
+                    "rpz" => {
+                        let (name, path) = match Self::split(value) {
+                            Some(d) => d,
+                            None => invalid!(InvalidType::Other),
+                        };
+                        let name = match Self::hostname(name) {
+                            Ok(name) => name,
+                            Err(kind) => invalid!(kind),
+                        };
+                        let (records, invalid) = zonefile::load(&name, self.relative(path)).await?;
+                        config.invalid.extend(invalid);
+                        config.rpz.extend(Rpz::from_records(&name, records));
+                    }
+                    "import-blocklist" => {
+                        let (format, path) = match Self::split(value) {
+                            Some(d) => d,
+                            None => invalid!(InvalidType::Other),
+                        };
+                        let format = match format.parse() {
+                            Ok(format) => format,
+                            Err(_) => invalid!(InvalidType::BlockFormat),
+                        };
+                        let (blocklist, invalid) = block::load(format, self.relative(path)).await?;
+                        config.invalid.extend(invalid);
+                        config.block.extend(blocklist);
+                    }
+                    "import" => {
+                        let path = self.relative(value);
                         config.extend(Parser::new(path).await?.parse().await?);
                     }
                     _ => match Self::record(key, value) {
@@ -292,6 +1346,23 @@ impl Parser {
                 }
             }
 
+            // A view left open at the end of the file
+            config.views.extend(view);
+
+            for (i, source, addr) in &binds {
+                if binds
+                    .iter()
+                    .any(|(_, _, wildcard)| view::overlaps(wildcard, addr))
+                {
+                    config.invalid.push(Invalid {
+                        path: self.path.clone(),
+                        line: i + 1,
+                        source: source.clone(),
+                        kind: InvalidType::Bind,
+                    });
+                }
+            }
+
             Ok(config)
         }
         .boxed()
@@ -326,20 +1397,193 @@ mod tests {
             vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53)]
         );
 
-        let ip_addresses: Vec<_> = config.hosts.record.iter().map(|(_, ip)| *ip).collect();
+        let records: Vec<_> = config
+            .hosts
+            .record
+            .iter()
+            .map(|(_, record)| record.to_string())
+            .collect();
         assert_eq!(
-            ip_addresses,
+            records,
             vec![
-                IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
-                IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)),
-                IpAddr::V4(Ipv4Addr::new(3, 3, 3, 3)),
-                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-                IpAddr::V4(Ipv4Addr::new(4, 4, 4, 4)),
+                IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)).to_string(),
+                IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)).to_string(),
+                IpAddr::V4(Ipv4Addr::new(3, 3, 3, 3)).to_string(),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED).to_string(),
+                IpAddr::V4(Ipv4Addr::new(4, 4, 4, 4)).to_string(),
+                "-> example.com".to_string(),
+                "~> example.com".to_string(),
+                "MX 10 mail.example.com".to_string(),
+                "TXT \"v=spf1 -all\"".to_string(),
+                "SRV 10 5 5060 sip.example.com".to_string(),
+                "CAA 0 issue \"letsencrypt.org\"".to_string(),
             ]
         );
 
+        let zones: Vec<_> = config.zones.iter().map(|zone| zone.name.as_str()).collect();
+        assert_eq!(zones, vec!["home.arpa"]);
+
         assert_eq!(config.timeout, Some(Duration::from_secs(2)));
 
+        assert!(config.allow.find("login.example.com").is_some());
+
+        assert!(config.filter_aaaa.find("www.example.com").is_some());
+        assert!(!config.filter_aaaa_all);
+        assert_eq!(
+            config.deny_types,
+            vec![
+                QueryType::UNKNOWN(65),
+                QueryType::UNKNOWN(64),
+                QueryType::ANY
+            ]
+        );
+
+        assert_eq!(config.trusted_ecs, vec![Cidr::new("10.0.0.53/32").unwrap()]);
+
+        assert_eq!(config.views.len(), 1);
+        let view = &config.views[0];
+        assert_eq!(view.name, "vpn");
+        assert_eq!(view.proxy, vec!["10.0.0.53:53".parse().unwrap()]);
+        assert_eq!(view.hosts.iter().len(), 1);
+
         Ok(())
     }
+
+    #[test]
+    fn parse_reverse() {
+        assert_eq!(
+            parse_arpa("4.3.2.1.in-addr.arpa"),
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+        assert_eq!(
+            parse_arpa("b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa"),
+            Some(IpAddr::V6("4321:0:1:2:3:4:567:89ab".parse().unwrap()))
+        );
+        assert_eq!(parse_arpa("3.2.1.in-addr.arpa"), None);
+        assert_eq!(parse_arpa("example.com"), None);
+
+        let mut hosts = Hosts::new();
+        hosts.push(Parser::record("example.com", "1.2.3.4").unwrap());
+        hosts.push(Parser::record("*.example.com", "1.2.3.5").unwrap());
+        hosts.push(Parser::record("example.net", "1.2.3.4").unwrap());
+        assert_eq!(hosts.reverse("4.3.2.1.in-addr.arpa"), Some("example.com"));
+        assert_eq!(hosts.reverse("5.3.2.1.in-addr.arpa"), None);
+    }
+
+    #[test]
+    fn empty_non_terminal() {
+        let mut hosts = Hosts::new();
+        hosts.push(Parser::record("a.b.home.arpa", "10.0.0.1").unwrap());
+        hosts.push(Parser::record("*.c.home.arpa", "10.0.0.2").unwrap());
+        assert!(hosts.exists("a.b.home.arpa"));
+        assert!(hosts.exists("b.home.arpa"));
+        assert!(hosts.exists("c.home.arpa"));
+        assert!(hosts.exists("x.c.home.arpa"));
+        assert!(!hosts.exists("d.home.arpa"));
+        assert!(!hosts.contains("b.home.arpa"));
+    }
+
+    #[test]
+    fn parse_record() {
+        let (_, record) = Parser::record("example.com", r#"TXT "a \"b\"" c"#).unwrap();
+        assert_eq!(record.to_string(), r#"TXT "a \"b\"" "c""#);
+        // Written as read back
+        let (_, record) = Parser::record("example.com", r#"TXT "a\\b\u{e9}""#).unwrap();
+        let (_, again) = Parser::record("example.com", &record.to_string()).unwrap();
+        assert_eq!(again.to_string(), record.to_string());
+        let (_, record) = Parser::record("example.com", r#"CAA 0 issue "ca; \"x\"""#).unwrap();
+        assert_eq!(record.to_string(), r#"CAA 0 issue "ca; \"x\"""#);
+
+        let (_, record) = Parser::record("example.com", "mx 10 Mail.Example.com.").unwrap();
+        assert_eq!(record.to_string(), "MX 10 mail.example.com");
+        // Type names are shared with deny-type
+        let (_, record) = Parser::record("example.com", "TYPE15 10 mail.example.com").unwrap();
+        assert_eq!(record.to_string(), "MX 10 mail.example.com");
+        assert_eq!(Parser::qtype("TYPE15"), Some(QueryType::MX));
+
+        let (_, record) = Parser::record("_sip._tcp.example.com", "SRV 10 5 5060 sip").unwrap();
+        assert_eq!(record.to_string(), "SRV 10 5 5060 sip");
+
+        assert!(matches!(
+            Parser::record("example.com", "MX mail.example.com"),
+            Err(InvalidType::Record)
+        ));
+        assert!(matches!(
+            Parser::record("example.com", "SRV 10 5 mail.example.com"),
+            Err(InvalidType::Record)
+        ));
+        assert!(matches!(
+            Parser::record("example.com", r#"TXT "v=spf1"#),
+            Err(InvalidType::Record)
+        ));
+
+        assert_eq!(
+            Parser::strip("example.com 1.1.1.1 # comment"),
+            "example.com 1.1.1.1"
+        );
+        assert_eq!(
+            Parser::strip(r##"example.com TXT "a#b" "c\"#d" # comment"##),
+            r##"example.com TXT "a#b" "c\"#d""##
+        );
+        assert_eq!(
+            Parser::strip(r##"example.com CAA 0 issue "ca.example; #x""##),
+            r##"example.com CAA 0 issue "ca.example; #x""##
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_record() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("updns-remove-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# hosts\r\na.test 10.0.0.1\r\nview vpn {\r\nc.test 10.8.0.3\r\n}\r\nb.test 10.0.0.2\r\nc.test 10.0.0.3",
+        )
+        .await?;
+
+        let removed = Parser::new(&path)
+            .await?
+            .remove(|matcher, _| matcher.as_static() == Some("c.test"))
+            .await?;
+        assert_eq!(removed, 1);
+        assert_eq!(
+            fs::read_to_string(&path).await?,
+            "# hosts\r\na.test 10.0.0.1\r\nview vpn {\r\nc.test 10.8.0.3\r\n}\r\nb.test 10.0.0.2"
+        );
+
+        Parser::new(&path).await?.add("d.test", "10.0.0.4").await?;
+        Parser::new(&path)
+            .await?
+            .remove(|matcher, _| matcher.as_static() == Some("a.test"))
+            .await?;
+        let content = fs::read_to_string(&path).await?;
+        fs::remove_file(&path).await?;
+        assert_eq!(
+            content,
+            "# hosts\r\nview vpn {\r\nc.test 10.8.0.3\r\n}\r\nb.test 10.0.0.2\r\nd.test  10.0.0.4"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_secondary() {
+        let secondary = Parser::secondary("Example.test. from 10.0.0.5").unwrap();
+        assert_eq!(secondary.name, "example.test");
+        assert_eq!(secondary.primary, "10.0.0.5:53".parse().unwrap());
+
+        let secondary = Parser::secondary("example.test from [fd00::5]:5353").unwrap();
+        assert_eq!(secondary.primary, "[fd00::5]:5353".parse().unwrap());
+
+        assert!(Parser::secondary("example.test 10.0.0.5").is_err());
+        assert!(Parser::secondary("example.test from example.com").is_err());
+
+        let secondary = Parser::secondary("example.test from 10.0.0.5 key ddns.key").unwrap();
+        assert_eq!(secondary.key.as_deref(), Some("ddns.key"));
+        assert!(Parser::secondary("example.test from 10.0.0.5 ddns.key").is_err());
+
+        assert_eq!(
+            Parser::zone_key("Home.arpa key ddns.key").unwrap(),
+            ("home.arpa".to_string(), "ddns.key".to_string())
+        );
+        assert!(Parser::zone_key("home.arpa ddns.key").is_err());
+    }
 }
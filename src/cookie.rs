@@ -0,0 +1,199 @@
+// DNS cookies (RFC 7873), server cookies are built as in RFC 9018
+// with HMAC-SHA256 in place of SipHash, only this server has to verify them
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const OPTION_CODE: u16 = 10;
+// Extended rcode of a response to a server cookie that is not valid
+pub const BADCOOKIE: u16 = 23;
+
+const CLIENT_LEN: usize = 8;
+const VERSION: u8 = 1;
+// Version, reserved, timestamp and hash
+const SERVER_LEN: usize = 16;
+
+// The secret changes every hour, cookies of the previous one are still accepted
+const ROTATE_INTERVAL: Duration = Duration::from_secs(3600);
+// Age of a valid server cookie, a new one is sent after half of it
+const MAX_AGE: u32 = 3600;
+const MAX_FUTURE: u32 = 300;
+
+// A client cookie alone, or with a server cookie of 8 to 32 bytes
+pub fn well_formed(cookie: &[u8]) -> bool {
+    cookie.len() == CLIENT_LEN || (16..=40).contains(&cookie.len())
+}
+
+// Whether the client sent a server cookie too
+pub fn has_server(cookie: &[u8]) -> bool {
+    cookie.len() > CLIENT_LEN
+}
+
+fn timestamp() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+// From the random generator of the system, the cookies can not be predicted
+fn random_secret() -> [u8; 16] {
+    let mut secret = [0; 16];
+    getrandom::getrandom(&mut secret).expect("No random generator for the cookie secret");
+    secret
+}
+
+fn hash(secret: &[u8], parts: &[&[u8]]) -> [u8; 8] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&mac.finalize().into_bytes()[..8]);
+    hash
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+#[derive(Debug)]
+pub struct Cookies {
+    secret: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+    // The last cookie of each upstream, client and server cookie
+    upstreams: HashMap<SocketAddr, Vec<u8>>,
+}
+
+impl Cookies {
+    pub fn new() -> Cookies {
+        let secret = random_secret();
+        Cookies {
+            secret,
+            previous: secret,
+            rotated: Instant::now(),
+            upstreams: HashMap::new(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= ROTATE_INTERVAL {
+            self.previous = self.secret;
+            self.secret = random_secret();
+            self.rotated = Instant::now();
+            self.upstreams.clear();
+        }
+    }
+
+    fn server_hash(secret: &[u8], client: &[u8], header: &[u8], ip: IpAddr) -> [u8; 8] {
+        hash(secret, &[client, header, &ip_bytes(ip)])
+    }
+
+    // The client cookie followed by a new server cookie
+    fn create(&self, client: &[u8], ip: IpAddr) -> Vec<u8> {
+        let mut cookie = client.to_vec();
+        cookie.extend([VERSION, 0, 0, 0]);
+        cookie.extend(timestamp().to_be_bytes());
+        let hash = Self::server_hash(&self.secret, client, &cookie[CLIENT_LEN..], ip);
+        cookie.extend(hash);
+        cookie
+    }
+
+    // Whether the server cookie was made by this server for the client
+    pub fn verify(&mut self, cookie: &[u8], ip: IpAddr) -> bool {
+        self.rotate();
+        if cookie.len() != CLIENT_LEN + SERVER_LEN || cookie[CLIENT_LEN] != VERSION {
+            return false;
+        }
+        let (client, server) = cookie.split_at(CLIENT_LEN);
+        let time = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+        let now = timestamp();
+        if now.wrapping_sub(time) > MAX_AGE && time.wrapping_sub(now) > MAX_FUTURE {
+            return false;
+        }
+        [self.secret, self.previous]
+            .iter()
+            .any(|secret| Self::server_hash(secret, client, &server[..8], ip) == server[8..])
+    }
+
+    // The cookie of a response, a valid one is kept until it is half expired
+    pub fn respond(&mut self, cookie: &[u8], ip: IpAddr) -> Vec<u8> {
+        if self.verify(cookie, ip) {
+            let time = u32::from_be_bytes([cookie[12], cookie[13], cookie[14], cookie[15]]);
+            if timestamp().wrapping_sub(time) < MAX_AGE / 2 {
+                return cookie.to_vec();
+            }
+        }
+        self.create(&cookie[..CLIENT_LEN], ip)
+    }
+
+    fn client_cookie(&self, upstream: SocketAddr) -> Vec<u8> {
+        hash(&self.secret, &[&ip_bytes(upstream.ip())]).to_vec()
+    }
+
+    // The cookie sent to an upstream, with its server cookie once it is known
+    pub fn upstream(&mut self, upstream: SocketAddr) -> Vec<u8> {
+        self.rotate();
+        let client = self.client_cookie(upstream);
+        match self.upstreams.get(&upstream) {
+            Some(cookie) if cookie.starts_with(&client) => cookie.clone(),
+            _ => client,
+        }
+    }
+
+    // A response without our client cookie is not an answer to our query
+    pub fn learn(&mut self, upstream: SocketAddr, cookie: &[u8]) -> bool {
+        if !cookie.starts_with(&self.client_cookie(upstream)) {
+            return false;
+        }
+        if well_formed(cookie) && cookie.len() > CLIENT_LEN {
+            self.upstreams.insert(upstream, cookie.to_vec());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies() {
+        let mut cookies = Cookies::new();
+        let ip = "192.0.2.1".parse().unwrap();
+        let client = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        assert!(!cookies.verify(&client, ip));
+        let cookie = cookies.respond(&client, ip);
+        assert_eq!(cookie.len(), 24);
+        assert!(well_formed(&cookie));
+        assert!(cookies.verify(&cookie, ip));
+        assert!(!cookies.verify(&cookie, "192.0.2.2".parse().unwrap()));
+        assert_eq!(cookies.respond(&cookie, ip), cookie);
+
+        let mut forged = cookie.clone();
+        forged[23] ^= 1;
+        assert!(!cookies.verify(&forged, ip));
+        let mut other = cookie.clone();
+        other[0] ^= 1;
+        assert!(!cookies.verify(&other, ip));
+
+        let upstream = "198.51.100.1:53".parse().unwrap();
+        let sent = cookies.upstream(upstream);
+        assert_eq!(sent.len(), 8);
+        let mut answer = sent.clone();
+        answer.extend([9; 16]);
+        assert!(cookies.learn(upstream, &answer));
+        assert_eq!(cookies.upstream(upstream), answer);
+        assert!(!cookies.learn(upstream, &[0; 24]));
+    }
+}
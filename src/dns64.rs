@@ -0,0 +1,178 @@
+// DNS64, AAAA records synthesized from A records for IPv6-only clients (RFC 6147)
+// The IPv4 address is embedded in the prefix as in RFC 6052
+
+use crate::cidr::Cidr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use updns::DnsRecord;
+
+const PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+#[derive(Debug, Clone)]
+pub struct Dns64 {
+    prefix: Cidr,
+    // AAAA records in these ranges are ignored, A records are not synthesized
+    exclude: Vec<Cidr>,
+    // Clients answered with synthesized records, every client if empty
+    clients: Vec<Cidr>,
+}
+
+impl Dns64 {
+    pub fn new(prefix: Cidr) -> Option<Dns64> {
+        if !prefix.addr().is_ipv6() || !PREFIX_LENGTHS.contains(&prefix.prefix()) {
+            return None;
+        }
+        Some(Dns64 {
+            prefix,
+            // IPv4-mapped addresses can not be used by an IPv6-only client
+            exclude: Cidr::new("::ffff:0:0/96").into_iter().collect(),
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn exclude(&mut self, cidr: Cidr) {
+        self.exclude.push(cidr);
+    }
+
+    pub fn client(&mut self, cidr: Cidr) {
+        self.clients.push(cidr);
+    }
+
+    pub fn serves(&self, ip: &IpAddr) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // Compared without unmapping, ::ffff:0:0/96 would never match otherwise
+    fn excluded(&self, ip: IpAddr) -> bool {
+        self.exclude
+            .iter()
+            .any(|cidr| Cidr::from_addr(ip, cidr.prefix()) == Some(*cidr))
+    }
+
+    // Whether the answer has an AAAA record outside the excluded ranges
+    pub fn has_aaaa(&self, answers: &[DnsRecord]) -> bool {
+        answers.iter().any(|record| match record {
+            DnsRecord::AAAA { addr, .. } => !self.excluded(IpAddr::V6(*addr)),
+            _ => false,
+        })
+    }
+
+    // The byte after 64 bits is left zero
+    fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = match self.prefix.addr() {
+            IpAddr::V6(v6) => v6.octets(),
+            IpAddr::V4(_) => unreachable!(),
+        };
+        let mut pos = self.prefix.prefix() as usize / 8;
+        for octet in ip.octets() {
+            if pos == 8 {
+                pos += 1;
+            }
+            octets[pos] = octet;
+            pos += 1;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    // Canonical names are kept, A records become AAAA records with a TTL of at most ttl
+    pub fn synthesize(&self, answers: Vec<DnsRecord>, ttl: Option<u32>) -> Vec<DnsRecord> {
+        answers
+            .into_iter()
+            .filter_map(|record| match record {
+                DnsRecord::A {
+                    domain,
+                    addr,
+                    ttl: a_ttl,
+                } if !self.excluded(IpAddr::V4(addr)) => Some(DnsRecord::AAAA {
+                    domain,
+                    addr: self.embed(addr),
+                    ttl: ttl.map_or(a_ttl, |ttl| ttl.min(a_ttl)),
+                }),
+                DnsRecord::CNAME { .. } => Some(record),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthesize() {
+        let dns64 = |prefix| Dns64::new(Cidr::new(prefix).unwrap());
+        assert!(dns64("64:ff9b::/80").is_none());
+        assert!(dns64("10.0.0.0/8").is_none());
+
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        let embedded = |prefix| dns64(prefix).unwrap().embed(ip);
+        assert_eq!(
+            embedded("64:ff9b::/96"),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            embedded("2001:db8::/32"),
+            "2001:db8:c000:221::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            embedded("2001:db8:100::/40"),
+            "2001:db8:1c0:2:21::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            embedded("2001:db8:122:300::/56"),
+            "2001:db8:122:3c0:0:221::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            embedded("2001:db8:122:344::/64"),
+            "2001:db8:122:344:c0:2:2100:0".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let mut dns64 = dns64("64:ff9b::/96").unwrap();
+        dns64.exclude(Cidr::new("10.0.0.0/8").unwrap());
+        let aaaa = |addr: &str| DnsRecord::AAAA {
+            domain: "example.com".to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: 60,
+        };
+        assert!(dns64.has_aaaa(&[aaaa("2001:db8::1")]));
+        assert!(!dns64.has_aaaa(&[aaaa("::ffff:192.0.2.1")]));
+
+        let a = |addr: &str| DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: 600,
+        };
+        let cname = DnsRecord::CNAME {
+            domain: "example.com".to_string(),
+            host: "www.example.com".to_string(),
+            ttl: 60,
+        };
+        let records = dns64.synthesize(
+            vec![cname.clone(), a("192.0.2.33"), a("10.0.0.1")],
+            Some(300),
+        );
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], cname);
+        assert_eq!(
+            records[1],
+            DnsRecord::AAAA {
+                domain: "www.example.com".to_string(),
+                addr: "64:ff9b::c000:221".parse().unwrap(),
+                ttl: 300,
+            }
+        );
+    }
+
+    #[test]
+    fn serves() {
+        let mut dns64 = Dns64::new(Cidr::new("64:ff9b::/96").unwrap()).unwrap();
+        let lab = "2001:db8:1::5".parse().unwrap();
+        let office = "2001:db8:2::5".parse().unwrap();
+        assert!(dns64.serves(&lab));
+        assert!(dns64.serves(&office));
+
+        dns64.client(Cidr::new("2001:db8:1::/48").unwrap());
+        assert!(dns64.serves(&lab));
+        assert!(!dns64.serves(&office));
+    }
+}
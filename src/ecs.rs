@@ -0,0 +1,158 @@
+// EDNS client subnet (RFC 7871)
+
+use crate::{block::is_private, cidr::Cidr};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    result,
+    str::FromStr,
+};
+
+pub const OPTION_CODE: u16 = 8;
+
+const FAMILY_V4: u16 = 1;
+const FAMILY_V6: u16 = 2;
+
+// The subnet sent to the upstreams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecs {
+    Strip,
+    // Prefix lengths of IPv4 and IPv6 clients
+    Prefix(u8, u8),
+}
+
+// strip  or  24 56
+impl FromStr for Ecs {
+    type Err = ();
+
+    fn from_str(text: &str) -> result::Result<Ecs, ()> {
+        if text.eq_ignore_ascii_case("strip") {
+            return Ok(Ecs::Strip);
+        }
+        let mut fields = text.split_ascii_whitespace();
+        let v4 = fields.next().and_then(|v4| v4.parse().ok());
+        let v6 = fields.next().and_then(|v6| v6.parse().ok());
+        match (v4, v6, fields.next()) {
+            (Some(v4), Some(v6), None) if v4 <= 32 && v6 <= 128 => Ok(Ecs::Prefix(v4, v6)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Ecs {
+    // The subnet of a query to the upstreams, a client may ask for none with a /0
+    // Private addresses say nothing about the location of a client and are not sent
+    pub fn subnet(&self, client: IpAddr, query: Option<Subnet>) -> Option<Subnet> {
+        let (v4, v6) = match self {
+            Ecs::Strip => return None,
+            Ecs::Prefix(v4, v6) => (*v4, *v6),
+        };
+        let addr = match query {
+            Some(subnet) if subnet.cidr.prefix() == 0 => return Some(subnet),
+            Some(subnet) => subnet.cidr.addr(),
+            None if is_private(&client) => return None,
+            None => client.to_canonical(),
+        };
+        let prefix = match addr {
+            IpAddr::V4(_) => v4,
+            IpAddr::V6(_) => v6,
+        };
+        let prefix = query.map_or(prefix, |subnet| prefix.min(subnet.cidr.prefix()));
+        Some(Subnet {
+            cidr: Cidr::from_addr(addr, prefix)?,
+            scope: 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    pub cidr: Cidr,
+    // The prefix the answer is valid for, set by the upstream
+    // Answers are not cached, the scope is only passed back to the client
+    pub scope: u8,
+}
+
+impl Subnet {
+    // Family, source prefix, scope prefix, the network bytes of the address
+    pub fn parse(data: &[u8]) -> Option<Subnet> {
+        let family = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+        let (prefix, scope) = (*data.get(2)?, *data.get(3)?);
+        let bytes = &data[4..];
+        if bytes.len() != (prefix as usize).div_ceil(8) {
+            return None;
+        }
+        let addr = match family {
+            FAMILY_V4 if bytes.len() <= 4 => {
+                let mut octets = [0; 4];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            FAMILY_V6 if bytes.len() <= 16 => {
+                let mut octets = [0; 16];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(Subnet {
+            cidr: Cidr::from_addr(addr, prefix)?,
+            scope,
+        })
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let (family, octets) = match self.cidr.addr() {
+            IpAddr::V4(addr) => (FAMILY_V4, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (FAMILY_V6, addr.octets().to_vec()),
+        };
+        let prefix = self.cidr.prefix();
+        let mut data = family.to_be_bytes().to_vec();
+        data.extend([prefix, self.scope]);
+        data.extend(&octets[..(prefix as usize).div_ceil(8)]);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnet() {
+        assert_eq!("strip".parse(), Ok(Ecs::Strip));
+        assert_eq!("24 56".parse(), Ok(Ecs::Prefix(24, 56)));
+        assert!("24".parse::<Ecs>().is_err());
+        assert!("33 56".parse::<Ecs>().is_err());
+
+        let ecs = Ecs::Prefix(24, 56);
+        let subnet = ecs.subnet("203.0.113.77".parse().unwrap(), None).unwrap();
+        assert_eq!(subnet.cidr, Cidr::new("203.0.113.0/24").unwrap());
+        assert_eq!(subnet.to_bytes(), [0, 1, 24, 0, 203, 0, 113]);
+        assert_eq!(Subnet::parse(&subnet.to_bytes()), Some(subnet));
+
+        let subnet = ecs
+            .subnet("2001:db8:1:2:3::1".parse().unwrap(), None)
+            .unwrap();
+        assert_eq!(subnet.cidr, Cidr::new("2001:db8:1::/56").unwrap());
+        assert_eq!(Subnet::parse(&subnet.to_bytes()), Some(subnet));
+
+        assert_eq!(ecs.subnet("192.168.1.2".parse().unwrap(), None), None);
+        assert_eq!(
+            Ecs::Strip.subnet("203.0.113.77".parse().unwrap(), None),
+            None
+        );
+
+        // The subnet of a forwarding client, no more specific than configured
+        let client = "10.0.0.1".parse().unwrap();
+        let query = Subnet::parse(&[0, 1, 32, 0, 198, 51, 100, 9]);
+        assert_eq!(
+            ecs.subnet(client, query).unwrap().cidr,
+            Cidr::new("198.51.100.0/24").unwrap()
+        );
+        let query = Subnet::parse(&[0, 1, 0, 0]);
+        assert_eq!(ecs.subnet(client, query), query);
+
+        assert_eq!(Subnet::parse(&[0, 1, 24, 0, 203, 0]), None);
+        assert_eq!(Subnet::parse(&[0, 3, 0, 0]), None);
+    }
+}
@@ -8,14 +8,19 @@ use std::io::{Error, ErrorKind, Result};
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_size(512)
+    }
+
+    // Messages over TCP can be up to 65535 bytes
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: vec![0; size],
             pos: 0,
         }
     }
@@ -37,7 +42,7 @@ impl BytePacketBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
         let res = self.buf[self.pos];
@@ -47,14 +52,14 @@ impl BytePacketBuffer {
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
         Ok(&self.buf[start..start + len as usize])
@@ -126,7 +131,7 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
         self.buf[self.pos] = val;
@@ -179,6 +184,23 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    // A character-string, its length in a single byte
+    fn write_string(&mut self, text: &str) -> Result<()> {
+        if text.len() > 0xFF {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Character string exceeds 255 bytes of length",
+            ));
+        }
+
+        self.write_u8(text.len() as u8)?;
+        for b in text.as_bytes() {
+            self.write_u8(*b)?;
+        }
+
+        Ok(())
+    }
+
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
         self.buf[pos] = val;
 
@@ -201,6 +223,11 @@ pub enum ResultCode {
     NXDOMAIN = 3,
     NOTIMP = 4,
     REFUSED = 5,
+    YXDOMAIN = 6,
+    YXRRSET = 7,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10,
 }
 
 impl ResultCode {
@@ -211,6 +238,11 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
             0 | _ => ResultCode::NOERROR,
         }
     }
@@ -323,8 +355,18 @@ pub enum QueryType {
     A,     // 1
     NS,    // 2
     CNAME, // 5
+    SOA,   // 6
+    PTR,   // 12
+    HINFO, // 13
     MX,    // 15
+    TXT,   // 16
     AAAA,  // 28
+    SRV,   // 33
+    TSIG,  // 250
+    IXFR,  // 251
+    AXFR,  // 252
+    ANY,   // 255
+    CAA,   // 257
 }
 
 impl QueryType {
@@ -334,8 +376,18 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
+            QueryType::HINFO => 13,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::TSIG => 250,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::ANY => 255,
+            QueryType::CAA => 257,
         }
     }
 
@@ -344,8 +396,18 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            13 => QueryType::HINFO,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            250 => QueryType::TSIG,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            255 => QueryType::ANY,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -387,10 +449,11 @@ impl DnsQuestion {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub enum DnsRecord {
+    // The data is kept as it is, written back unchanged
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -408,20 +471,84 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
+    HINFO {
+        domain: String,
+        cpu: String,
+        os: String,
+        ttl: u32,
+    }, // 13
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    TXT {
+        domain: String,
+        data: Vec<String>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        ttl: u32,
+    }, // 33
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: String,
+        ttl: u32,
+    }, // 257
+    TSIG {
+        domain: String,
+        algorithm: String,
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other: Vec<u8>,
+    }, // 250
 }
 
 impl DnsRecord {
+    // The class is only meaningful in UPDATE messages
+    pub fn read_with_class(buffer: &mut BytePacketBuffer) -> Result<(DnsRecord, u16)> {
+        let start = buffer.pos();
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain)?;
+        buffer.step(2)?;
+        let class = buffer.read_u16()?;
+        buffer.seek(start)?;
+
+        Ok((DnsRecord::read(buffer)?, class))
+    }
+
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
@@ -432,6 +559,16 @@ impl DnsRecord {
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
+        // Records without data appear in the prerequisite and update sections of UPDATE messages
+        if data_len == 0 {
+            return Ok(DnsRecord::UNKNOWN {
+                domain: domain,
+                qtype: qtype_num,
+                data: Vec::new(),
+                ttl: ttl,
+            });
+        }
+
         match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
@@ -490,6 +627,34 @@ impl DnsRecord {
                     ttl: ttl,
                 })
             }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                Ok(DnsRecord::SOA {
+                    domain: domain,
+                    mname: mname,
+                    rname: rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl: ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain: domain,
+                    host: ptr,
+                    ttl: ttl,
+                })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -502,13 +667,113 @@ impl DnsRecord {
                     ttl: ttl,
                 })
             }
-            QueryType::UNKNOWN(_) => {
+            QueryType::HINFO => {
+                let mut data = Vec::new();
+                for _ in 0..2 {
+                    let len = buffer.read()?;
+                    let pos = buffer.pos();
+                    let text = String::from_utf8_lossy(buffer.get_range(pos, len as usize)?);
+                    data.push(text.to_string());
+                    buffer.step(len as usize)?;
+                }
+                let os = data.pop().unwrap_or_default();
+                let cpu = data.pop().unwrap_or_default();
+
+                Ok(DnsRecord::HINFO {
+                    domain: domain,
+                    cpu: cpu,
+                    os: os,
+                    ttl: ttl,
+                })
+            }
+            QueryType::TXT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut data = Vec::new();
+                while buffer.pos() < end {
+                    let len = buffer.read()?;
+                    let pos = buffer.pos();
+                    let text = String::from_utf8_lossy(buffer.get_range(pos, len as usize)?);
+                    data.push(text.to_string());
+                    buffer.step(len as usize)?;
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain: domain,
+                    data: data,
+                    ttl: ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut srv = String::new();
+                buffer.read_qname(&mut srv)?;
+
+                Ok(DnsRecord::SRV {
+                    domain: domain,
+                    priority: priority,
+                    weight: weight,
+                    port: port,
+                    host: srv,
+                    ttl: ttl,
+                })
+            }
+            QueryType::CAA => {
+                let flags = buffer.read()?;
+                let tag_len = buffer.read()? as usize;
+                let pos = buffer.pos();
+                let tag = String::from_utf8_lossy(buffer.get_range(pos, tag_len)?).to_string();
+                buffer.step(tag_len)?;
+                let value_len = (data_len as usize).saturating_sub(tag_len + 2);
+                let pos = buffer.pos();
+                let value = String::from_utf8_lossy(buffer.get_range(pos, value_len)?).to_string();
+                buffer.step(value_len)?;
+
+                Ok(DnsRecord::CAA {
+                    domain: domain,
+                    flags: flags,
+                    tag: tag,
+                    value: value,
+                    ttl: ttl,
+                })
+            }
+            QueryType::TSIG => {
+                let mut algorithm = String::new();
+                buffer.read_qname(&mut algorithm)?;
+                let time_signed = ((buffer.read_u16()? as u64) << 32) | (buffer.read_u32()? as u64);
+                let fudge = buffer.read_u16()?;
+                let mac_len = buffer.read_u16()? as usize;
+                let pos = buffer.pos();
+                let mac = buffer.get_range(pos, mac_len)?.to_vec();
+                buffer.step(mac_len)?;
+                let original_id = buffer.read_u16()?;
+                let error = buffer.read_u16()?;
+                let other_len = buffer.read_u16()? as usize;
+                let pos = buffer.pos();
+                let other = buffer.get_range(pos, other_len)?.to_vec();
+                buffer.step(other_len)?;
+
+                Ok(DnsRecord::TSIG {
+                    domain: domain,
+                    algorithm: algorithm,
+                    time_signed: time_signed,
+                    fudge: fudge,
+                    mac: mac,
+                    original_id: original_id,
+                    error: error,
+                    other: other,
+                })
+            }
+            _ => {
+                let pos = buffer.pos();
+                let data = buffer.get_range(pos, data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain: domain,
                     qtype: qtype_num,
-                    data_len: data_len,
+                    data: data,
                     ttl: ttl,
                 })
             }
@@ -572,6 +837,54 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
@@ -607,13 +920,232 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                logs::warn!("Skipping record: {:?}", self);
+            DnsRecord::HINFO {
+                ref domain,
+                ref cpu,
+                ref os,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::HINFO.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((2 + cpu.len() + os.len()) as u16)?;
+
+                for text in [cpu, os] {
+                    buffer.write_string(text)?;
+                }
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for text in data {
+                    buffer.write_string(text)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CAA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((2 + tag.len() + value.len()) as u16)?;
+
+                buffer.write_u8(flags)?;
+                buffer.write_string(tag)?;
+                for b in value.as_bytes() {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::TSIG {
+                ref domain,
+                ref algorithm,
+                time_signed,
+                fudge,
+                ref mac,
+                original_id,
+                error,
+                ref other,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TSIG.to_num())?;
+                // Class ANY and no TTL
+                buffer.write_u16(255)?;
+                buffer.write_u32(0)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(algorithm)?;
+                buffer.write_u16((time_signed >> 32) as u16)?;
+                buffer.write_u32(time_signed as u32)?;
+                buffer.write_u16(fudge)?;
+                buffer.write_u16(mac.len() as u16)?;
+                for b in mac {
+                    buffer.write_u8(*b)?;
+                }
+                buffer.write_u16(original_id)?;
+                buffer.write_u16(error)?;
+                buffer.write_u16(other.len() as u16)?;
+                for b in other {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
+
+    pub fn domain(&self) -> &str {
+        match *self {
+            DnsRecord::UNKNOWN { ref domain, .. }
+            | DnsRecord::A { ref domain, .. }
+            | DnsRecord::NS { ref domain, .. }
+            | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::SOA { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
+            | DnsRecord::HINFO { ref domain, .. }
+            | DnsRecord::MX { ref domain, .. }
+            | DnsRecord::TXT { ref domain, .. }
+            | DnsRecord::AAAA { ref domain, .. }
+            | DnsRecord::SRV { ref domain, .. }
+            | DnsRecord::CAA { ref domain, .. }
+            | DnsRecord::TSIG { ref domain, .. } => domain,
+        }
+    }
+
+    pub fn set_domain(&mut self, name: String) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut domain, .. }
+            | DnsRecord::A { ref mut domain, .. }
+            | DnsRecord::NS { ref mut domain, .. }
+            | DnsRecord::CNAME { ref mut domain, .. }
+            | DnsRecord::SOA { ref mut domain, .. }
+            | DnsRecord::PTR { ref mut domain, .. }
+            | DnsRecord::HINFO { ref mut domain, .. }
+            | DnsRecord::MX { ref mut domain, .. }
+            | DnsRecord::TXT { ref mut domain, .. }
+            | DnsRecord::AAAA { ref mut domain, .. }
+            | DnsRecord::SRV { ref mut domain, .. }
+            | DnsRecord::CAA { ref mut domain, .. }
+            | DnsRecord::TSIG { ref mut domain, .. } => *domain = name,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => ttl,
+            DnsRecord::TSIG { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, value: u32) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut ttl, .. }
+            | DnsRecord::A { ref mut ttl, .. }
+            | DnsRecord::NS { ref mut ttl, .. }
+            | DnsRecord::CNAME { ref mut ttl, .. }
+            | DnsRecord::SOA { ref mut ttl, .. }
+            | DnsRecord::PTR { ref mut ttl, .. }
+            | DnsRecord::HINFO { ref mut ttl, .. }
+            | DnsRecord::MX { ref mut ttl, .. }
+            | DnsRecord::TXT { ref mut ttl, .. }
+            | DnsRecord::AAAA { ref mut ttl, .. }
+            | DnsRecord::SRV { ref mut ttl, .. }
+            | DnsRecord::CAA { ref mut ttl, .. } => *ttl = value,
+            DnsRecord::TSIG { .. } => {}
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::HINFO { .. } => QueryType::HINFO,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+            DnsRecord::TSIG { .. } => QueryType::TSIG,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -623,6 +1155,8 @@ pub struct DnsPacket {
     pub answers: Vec<DnsRecord>,
     pub authorities: Vec<DnsRecord>,
     pub resources: Vec<DnsRecord>,
+    // Where the TSIG record starts, the signed part of the message ends there
+    pub tsig_offset: Option<usize>,
 }
 
 impl DnsPacket {
@@ -633,6 +1167,7 @@ impl DnsPacket {
             answers: Vec::new(),
             authorities: Vec::new(),
             resources: Vec::new(),
+            tsig_offset: None,
         }
     }
 
@@ -654,8 +1189,15 @@ impl DnsPacket {
             let rec = DnsRecord::read(buffer)?;
             result.authorities.push(rec);
         }
-        for _ in 0..result.header.resource_entries {
+        for i in 0..result.header.resource_entries {
+            let pos = buffer.pos();
             let rec = DnsRecord::read(buffer)?;
+            // TSIG is only valid as the last record
+            if let DnsRecord::TSIG { .. } = rec {
+                if i + 1 == result.header.resource_entries {
+                    result.tsig_offset = Some(pos);
+                }
+            }
             result.resources.push(rec);
         }
 
@@ -686,6 +1228,10 @@ impl DnsPacket {
         Ok(())
     }
 
+    pub fn tsig(&self) -> Option<&DnsRecord> {
+        self.tsig_offset.and_then(|_| self.resources.last())
+    }
+
     pub fn get_random_a(&self) -> Option<String> {
         if !self.answers.is_empty() {
             let a_record = &self.answers[0];
@@ -1,38 +1,116 @@
+mod block;
+mod cidr;
 mod cli;
 mod config;
+mod cookie;
+mod dns64;
+mod ecs;
 mod matcher;
+mod ratelimit;
+mod rewrite;
+mod rpz;
+mod secondary;
+mod tsig;
+mod update;
+mod view;
 mod watch;
+mod zone;
+mod zonefile;
 
+use block::{BlockMode, Blocklist, RejectMode};
+use cidr::{Acl, Cidr};
 use cli::{parse_args, Args, RunType};
-use config::{Config, Hosts, MultipleInvalid, Parser};
+use config::{Config, Hosts, MultipleInvalid, Parser, Record};
+use cookie::Cookies;
+use dns64::Dns64;
+use ecs::{Ecs, Subnet};
 use futures_util::StreamExt;
 use lazy_static::lazy_static;
 use logs::{error, info, warn};
+use ratelimit::{ClientLimit, Rrl, Verdict};
+use rewrite::Mapping;
+use rpz::{Action, Rpz};
+use secondary::Secondary;
 use std::{
     env,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
     process::Command,
-    time::Duration,
+    result,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
-    io::{Error, ErrorKind, Result},
-    net::UdpSocket,
-    sync::RwLock,
-    time::timeout,
+    io::{AsyncReadExt, AsyncWriteExt, Error, Result},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{Mutex, Notify, RwLock, Semaphore},
+    time::{sleep_until, timeout},
 };
+use tsig::{Key, Signer};
+use update::{Change, Update};
 use updns::*;
+use view::View;
 use watch::Watch;
+use zone::Zone;
 
 const CONFIG_FILE: [&str; 2] = [".updns", "config"];
 const WATCH_INTERVAL: Duration = Duration::from_millis(5000);
 const DEFAULT_BIND: &str = "0.0.0.0:53";
 const DEFAULT_PROXY: [&str; 2] = ["8.8.8.8:53", "1.1.1.1:53"];
 const DEFAULT_TIMEOUT: Duration = Duration::from_millis(2000);
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_millis(10000);
+// Connections over the limit are closed right away
+const MAX_TCP_CONNECTIONS: usize = 256;
+const FIRST_TRANSFER_RETRY: Duration = Duration::from_millis(60000);
+const NOTIFY_OPCODE: u8 = 4;
+const UPDATE_OPCODE: u8 = 5;
+const DEFAULT_TTL: u32 = 3600;
+const DEFAULT_SLIP: u32 = 2;
+// Queries are limited per IPv4 address, or per IPv6 /64 network
+const DEFAULT_CLIENT_PREFIX: (u8, u8) = (32, 64);
+// Clients without EDNS accept 512 bytes over UDP
+const MIN_UDP_SIZE: u16 = 512;
+const DEFAULT_MAX_UDP_SIZE: u16 = 1232;
+// Upstream answers are read up to this size
+const MAX_MESSAGE_SIZE: usize = 4096;
+const MAX_CNAME_CHAIN: usize = 8;
 
 lazy_static! {
     static ref PROXY: RwLock<Vec<SocketAddr>> = RwLock::new(Vec::new());
     static ref HOSTS: RwLock<Hosts> = RwLock::new(Hosts::new());
+    static ref ZONES: RwLock<Vec<Zone>> = RwLock::new(Vec::new());
+    static ref ALLOW_TRANSFER: RwLock<Vec<Cidr>> = RwLock::new(Vec::new());
+    static ref TRANSFER_KEYS: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+    static ref UPDATE_KEYS: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+    static ref ALLOW_UPDATE: RwLock<Vec<Cidr>> = RwLock::new(Vec::new());
+    // Dynamic updates are saved to the config file
+    static ref CONFIG_PATH: RwLock<PathBuf> = RwLock::new(PathBuf::new());
+    static ref KEYS: RwLock<Vec<Key>> = RwLock::new(Vec::new());
+    static ref BLOCK: RwLock<Blocklist> = RwLock::new(Blocklist::new());
+    static ref ALLOW: RwLock<Blocklist> = RwLock::new(Blocklist::new());
+    static ref BLOCK_MODE: RwLock<BlockMode> = RwLock::new(BlockMode::NxDomain);
+    static ref RPZ: RwLock<Rpz> = RwLock::new(Rpz::new());
+    static ref CLIENTS: RwLock<Acl> = RwLock::new(Acl::new());
+    static ref DENY_MODE: RwLock<RejectMode> = RwLock::new(RejectMode::Refuse);
+    static ref REBIND: RwLock<Option<RejectMode>> = RwLock::new(None);
+    static ref REBIND_ALLOW: RwLock<Blocklist> = RwLock::new(Blocklist::new());
+    static ref MAPS: RwLock<Vec<Mapping>> = RwLock::new(Vec::new());
+    // Proxied answers are clamped to the minimum and maximum TTL
+    static ref TTL: RwLock<(Option<u32>, Option<u32>)> = RwLock::new((None, None));
+    static ref SECONDARIES: RwLock<Vec<Secondary>> = RwLock::new(Vec::new());
+    // Wakes up the refresh of secondary zones
+    static ref REFRESH: Notify = Notify::new();
+    static ref CLIENT_LIMIT: Mutex<Option<ClientLimit>> = Mutex::new(None);
+    static ref RRL: Mutex<Option<Rrl>> = Mutex::new(None);
+    static ref COOKIES: Mutex<Cookies> = Mutex::new(Cookies::new());
+    static ref MAX_UDP_SIZE: RwLock<u16> = RwLock::new(DEFAULT_MAX_UDP_SIZE);
+    static ref VIEWS: RwLock<Vec<View>> = RwLock::new(Vec::new());
+    static ref ECS: RwLock<Option<Ecs>> = RwLock::new(None);
+    static ref TRUSTED_ECS: RwLock<Vec<Cidr>> = RwLock::new(Vec::new());
+    static ref DNS64: RwLock<Option<Dns64>> = RwLock::new(None);
+    // Every domain, or the matching ones
+    static ref FILTER_AAAA: RwLock<(bool, Blocklist)> = RwLock::new((false, Blocklist::new()));
+    static ref DENY_TYPES: RwLock<Vec<QueryType>> = RwLock::new(Vec::new());
     static ref TIMEOUT: RwLock<Duration> = RwLock::new(DEFAULT_TIMEOUT);
 }
 
@@ -60,15 +138,15 @@ async fn main() {
             }
         }
         RunType::PrintRecord => {
-            let mut config = force_get_config(&path).await;
+            let config = force_get_config(&path).await;
             let n = config
                 .hosts
                 .iter()
                 .map(|(m, _)| m.to_string().len())
                 .fold(0, |a, b| a.max(b));
 
-            for (host, ip) in config.hosts.iter() {
-                println!("{:domain$}    {}", host.to_string(), ip, domain = n);
+            for (host, record) in config.hosts.iter() {
+                println!("{:domain$}    {}", host.to_string(), record, domain = n);
             }
         }
         RunType::EditConfig => {
@@ -102,19 +180,76 @@ async fn main() {
                 );
             }
 
-            update_config(config.proxy, config.hosts, config.timeout).await;
+            let mut bind = std::mem::take(&mut config.bind);
+            for addr in config.views.iter().flat_map(|view| &view.bind) {
+                if !bind.contains(addr) {
+                    bind.push(*addr);
+                }
+            }
+            // Binding it would fail, the queries arrive on the wildcard address
+            let wildcards = bind.clone();
+            bind.retain(|addr| {
+                let overlapping = wildcards.iter().any(|w| view::overlaps(w, addr));
+                if overlapping {
+                    error!("Not binding '{}', a wildcard address takes the port", addr);
+                }
+                !overlapping
+            });
+            update_config(config).await;
+            *CONFIG_PATH.write().await = path.clone();
 
             // Run server
-            for addr in config.bind {
+            for addr in bind {
                 tokio::spawn(run_server(addr));
+                tokio::spawn(run_tcp_server(addr));
             }
+            tokio::spawn(run_secondaries());
             // watch config
             watch_config(path, WATCH_INTERVAL).await;
         }
     }
 }
 
-async fn update_config(mut proxy: Vec<SocketAddr>, hosts: Hosts, timeout: Option<Duration>) {
+async fn update_config(config: Config) {
+    let Config {
+        mut proxy,
+        hosts,
+        mut zones,
+        allow_transfer,
+        allow_update,
+        transfer_keys,
+        update_keys,
+        mut secondaries,
+        keys,
+        block,
+        allow,
+        block_mode,
+        rpz,
+        clients,
+        deny_mode,
+        rebind,
+        rebind_allow,
+        maps,
+        min_ttl,
+        max_ttl,
+        views,
+        rate_limit,
+        rrl,
+        rrl_slip,
+        rate_limit_prefix,
+        max_udp_size,
+        ecs,
+        trusted_ecs,
+        dns64,
+        dns64_exclude,
+        dns64_clients,
+        filter_aaaa,
+        filter_aaaa_all,
+        deny_types,
+        timeout,
+        ..
+    } = config;
+
     if proxy.is_empty() {
         proxy = DEFAULT_PROXY
             .iter()
@@ -126,10 +261,141 @@ async fn update_config(mut proxy: Vec<SocketAddr>, hosts: Hosts, timeout: Option
         let mut w = PROXY.write().await;
         *w = proxy;
     }
+    {
+        let old_hosts = HOSTS.read().await;
+        let mut w = ZONES.write().await;
+        let old_zones = std::mem::take(&mut *w);
+        for zone in zones.iter_mut() {
+            if let Some(old) = old_zones.iter().find(|old| old.name == zone.name) {
+                zone.update(old, &old_hosts);
+            }
+        }
+        // Transferred zones are kept until the next transfer
+        for old in old_zones {
+            let secondary = secondaries.iter().any(|s| s.name == old.name);
+            if secondary && !zones.iter().any(|zone| zone.name == old.name) {
+                zones.push(old);
+            }
+        }
+        *w = zones;
+    }
     {
         let mut w = HOSTS.write().await;
         *w = hosts;
     }
+    {
+        let mut w = SECONDARIES.write().await;
+        for secondary in secondaries.iter_mut() {
+            let old = w
+                .iter()
+                .find(|old| old.name == secondary.name && old.primary == secondary.primary);
+            if let Some(old) = old {
+                secondary.next_refresh = old.next_refresh;
+                secondary.last_refresh = old.last_refresh;
+            }
+        }
+        *w = secondaries;
+    }
+    REFRESH.notify_one();
+    {
+        let mut w = ALLOW_TRANSFER.write().await;
+        *w = allow_transfer;
+    }
+    {
+        let mut w = ALLOW_UPDATE.write().await;
+        *w = allow_update;
+    }
+    {
+        let mut w = TRANSFER_KEYS.write().await;
+        *w = transfer_keys;
+    }
+    {
+        let mut w = UPDATE_KEYS.write().await;
+        *w = update_keys;
+    }
+    {
+        let mut w = KEYS.write().await;
+        *w = keys;
+    }
+    {
+        let mut w = BLOCK.write().await;
+        *w = block;
+    }
+    {
+        let mut w = ALLOW.write().await;
+        *w = allow;
+    }
+    {
+        let mut w = BLOCK_MODE.write().await;
+        *w = block_mode.unwrap_or(BlockMode::NxDomain);
+    }
+    {
+        let mut w = RPZ.write().await;
+        *w = rpz;
+    }
+    {
+        let mut w = CLIENTS.write().await;
+        *w = clients;
+    }
+    {
+        let mut w = DENY_MODE.write().await;
+        *w = deny_mode.unwrap_or(RejectMode::Refuse);
+    }
+    {
+        let mut w = REBIND.write().await;
+        *w = rebind;
+    }
+    {
+        let mut w = REBIND_ALLOW.write().await;
+        *w = rebind_allow;
+    }
+    {
+        let mut w = MAPS.write().await;
+        *w = maps;
+    }
+    {
+        let mut w = TTL.write().await;
+        *w = (min_ttl, max_ttl);
+    }
+    let prefix = rate_limit_prefix.unwrap_or(DEFAULT_CLIENT_PREFIX);
+    *CLIENT_LIMIT.lock().await = rate_limit.map(|limit| ClientLimit::new(limit, prefix));
+    *RRL.lock().await = rrl.map(|limit| Rrl::new(limit, rrl_slip.unwrap_or(DEFAULT_SLIP)));
+    {
+        let mut w = MAX_UDP_SIZE.write().await;
+        *w = max_udp_size.unwrap_or(DEFAULT_MAX_UDP_SIZE);
+    }
+    {
+        let mut w = VIEWS.write().await;
+        *w = views;
+    }
+    {
+        let mut w = ECS.write().await;
+        *w = ecs;
+    }
+    {
+        let mut w = TRUSTED_ECS.write().await;
+        *w = trusted_ecs;
+    }
+    {
+        let mut w = DNS64.write().await;
+        *w = dns64.map(|mut dns64| {
+            for cidr in dns64_exclude {
+                dns64.exclude(cidr);
+            }
+            for cidr in dns64_clients {
+                dns64.client(cidr);
+            }
+            dns64
+        });
+    }
+    {
+        let mut w = FILTER_AAAA.write().await;
+        *w = (filter_aaaa_all, filter_aaaa);
+    }
+    {
+        let mut w = DENY_TYPES.write().await;
+        *w = deny_types;
+    }
     {
         let mut w = TIMEOUT.write().await;
         *w = timeout.unwrap_or(DEFAULT_TIMEOUT);
@@ -156,13 +422,156 @@ async fn watch_config(p: PathBuf, d: Duration) {
         info!("Reload the configuration file: {:?}", &p);
         if let Ok(parser) = Parser::new(&p).await {
             if let Ok(config) = parser.parse().await {
-                update_config(config.proxy, config.hosts, config.timeout).await;
                 config.invalid.print();
+                update_config(config).await;
+            }
+        }
+    }
+}
+
+// Refresh the secondary zones when their SOA timers are due, or on NOTIFY
+async fn run_secondaries() {
+    loop {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut next = None;
+        for secondary in SECONDARIES.read().await.iter() {
+            if secondary.next_refresh <= now {
+                due.push((
+                    secondary.name.clone(),
+                    secondary.primary,
+                    secondary.key.clone(),
+                ));
+            } else {
+                next = Some(next.map_or(secondary.next_refresh, |n: Instant| {
+                    n.min(secondary.next_refresh)
+                }));
+            }
+        }
+
+        if due.is_empty() {
+            match next {
+                Some(next) => {
+                    tokio::select! {
+                        _ = sleep_until(next.into()) => {}
+                        _ = REFRESH.notified() => {}
+                    }
+                }
+                None => REFRESH.notified().await,
+            }
+            continue;
+        }
+
+        for (name, primary, key) in due {
+            let refreshed = refresh_zone(&name, primary, key.as_deref()).await;
+            let mut secondaries = SECONDARIES.write().await;
+            if let Some(secondary) = secondaries.iter_mut().find(|s| s.name == name) {
+                let now = Instant::now();
+                match refreshed {
+                    Ok(wait) => {
+                        secondary.last_refresh = Some(now);
+                        secondary.next_refresh = now + wait;
+                    }
+                    Err(wait) => {
+                        secondary.next_refresh = now + wait;
+                        expire_zone(secondary).await;
+                    }
+                }
             }
         }
     }
 }
 
+// Returns the time until the next refresh, or until the retry on failure
+async fn refresh_zone(
+    name: &str,
+    primary: SocketAddr,
+    key: Option<&str>,
+) -> result::Result<Duration, Duration> {
+    let duration = *TIMEOUT.read().await;
+    let key = match key {
+        Some(key) => match KEYS.read().await.iter().find(|k| k.name == key) {
+            Some(key) => Some(key.clone()),
+            None => {
+                error!("Unknown key '{}' of zone '{}'", key, name);
+                return Err(FIRST_TRANSFER_RETRY);
+            }
+        },
+        None => None,
+    };
+    let timers = ZONES
+        .read()
+        .await
+        .iter()
+        .find(|zone| zone.name == name)
+        .map(|zone| (zone.serial(), zone.timers()));
+
+    let retry = timers.map_or(FIRST_TRANSFER_RETRY, |(_, (_, retry, _))| {
+        Duration::from_secs(retry as u64)
+    });
+
+    // Only transfer when the primary has a newer version
+    if let Some((serial, (refresh, _, _))) = timers {
+        match secondary::serial(name, primary, key.as_ref(), duration).await {
+            Ok(primary_serial) if primary_serial == serial => {
+                return Ok(Duration::from_secs(refresh as u64));
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!(
+                    "Failed to query SOA of '{}' from '{}' {:?}",
+                    name, primary, err
+                );
+                return Err(retry);
+            }
+        }
+    }
+
+    let records = match secondary::transfer(name, primary, key.as_ref(), duration).await {
+        Ok(records) => records,
+        Err(err) => {
+            error!("Failed to transfer '{}' from '{}' {:?}", name, primary, err);
+            return Err(retry);
+        }
+    };
+    let zone = match Zone::from_records(name.to_string(), records) {
+        Some(zone) => zone,
+        None => {
+            error!("Zone '{}' from '{}' has no SOA record", name, primary);
+            return Err(retry);
+        }
+    };
+
+    info!(
+        "Transferred zone '{}' serial {} from '{}'",
+        name,
+        zone.serial(),
+        primary
+    );
+    let (refresh, _, _) = zone.timers();
+    let mut zones = ZONES.write().await;
+    zones.retain(|zone| zone.name != name);
+    zones.push(zone);
+
+    Ok(Duration::from_secs(refresh as u64))
+}
+
+// Stop answering for a zone when the primary was unreachable longer than the expire timer
+async fn expire_zone(secondary: &Secondary) {
+    let mut zones = ZONES.write().await;
+    let expire = zones
+        .iter()
+        .find(|zone| zone.name == secondary.name)
+        .map(|zone| Duration::from_secs(zone.timers().2 as u64));
+
+    if let (Some(expire), Some(last)) = (expire, secondary.last_refresh) {
+        if last.elapsed() > expire {
+            warn!("Zone '{}' has expired", secondary.name);
+            zones.retain(|zone| zone.name != secondary.name);
+        }
+    }
+}
+
 async fn run_server(addr: SocketAddr) {
     let socket = match UdpSocket::bind(&addr).await {
         Ok(socket) => {
@@ -175,7 +584,8 @@ async fn run_server(addr: SocketAddr) {
     };
 
     loop {
-        let mut req = BytePacketBuffer::new();
+        // EDNS, signed and update messages can be larger than 512 bytes
+        let mut req = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
 
         let (len, src) = match socket.recv_from(&mut req.buf).await {
             Ok(r) => r,
@@ -185,32 +595,387 @@ async fn run_server(addr: SocketAddr) {
             }
         };
 
-        let res = match handle(req, len).await {
-            Ok(data) => data,
+        // Only UDP sources can be spoofed or flood the server cheaply,
+        // a client returning our cookie is not spoofed
+        let verified = match rewrite::option(&req.buf[..len], cookie::OPTION_CODE) {
+            Some(cookie) => COOKIES.lock().await.verify(cookie, src.ip()),
+            None => false,
+        };
+        if let Some(limit) = CLIENT_LIMIT.lock().await.as_mut() {
+            if !verified && !limit.allows(src.ip()) {
+                continue;
+            }
+        }
+
+        let res = match handle_message(req, len, src, addr, false).await {
+            Ok(mut data) if !data.is_empty() => data.remove(0),
+            Ok(_) => continue,
             Err(err) => {
                 error!("Processing request failed {:?}", err);
                 continue;
             }
         };
 
+        let res = match RRL
+            .lock()
+            .await
+            .as_mut()
+            .filter(|_| !verified)
+            .map(|rrl| rrl.check(src.ip(), &res))
+        {
+            Some(Verdict::Drop) => continue,
+            Some(Verdict::Slip) => rewrite::truncate(&res),
+            Some(Verdict::Send) | None => res,
+        };
+
         if let Err(err) = socket.send_to(&res, &src).await {
             error!("Replying to '{}' failed {:?}", &src, err);
         }
     }
 }
 
-async fn proxy(buf: &[u8]) -> Result<Vec<u8>> {
-    let proxy = PROXY.read().await;
+async fn run_tcp_server(addr: SocketAddr) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            exit!("Binding '{}' (tcp) failed\n{:?}", addr, err)
+        }
+    };
+
+    let connections = Arc::new(Semaphore::new(MAX_TCP_CONNECTIONS));
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(r) => r,
+            Err(err) => {
+                error!("Failed to accept connection {:?}", err);
+                continue;
+            }
+        };
+
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("Too many connections, closed '{}'", src);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(err) = serve_tcp(stream, src, addr).await {
+                error!("Processing connection from '{}' failed {:?}", src, err);
+            }
+            drop(permit);
+        });
+    }
+}
+
+// Every message is prefixed with a two byte length
+async fn serve_tcp(mut stream: TcpStream, src: SocketAddr, local: SocketAddr) -> Result<()> {
+    loop {
+        let len = match timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            // Closed by the client or idle
+            _ => return Ok(()),
+        };
+
+        // A client that stops in the middle of a message is disconnected
+        let mut req = BytePacketBuffer::with_size(len);
+        match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut req.buf)).await {
+            Ok(read) => read?,
+            Err(_) => return Ok(()),
+        };
+
+        for data in handle_message(req, len, src, local, true).await? {
+            stream.write_u16(data.len() as u16).await?;
+            stream.write_all(&data).await?;
+        }
+    }
+}
+
+// Responses to a request signed with TSIG are signed with the same key
+async fn handle_message(
+    mut req: BytePacketBuffer,
+    mut len: usize,
+    src: SocketAddr,
+    local: SocketAddr,
+    tcp: bool,
+) -> Result<Vec<Vec<u8>>> {
+    let mut request = DnsPacket::from_buffer(&mut req)?;
+
+    if !CLIENTS.read().await.allows(&src.ip()) {
+        warn!("Denied '{}'", src);
+        let mut res = vec![reject(request, *DENY_MODE.read().await)?];
+        res.retain(|data| !data.is_empty());
+        return Ok(res);
+    }
+
+    let mut signer = match Signer::verify(&req.buf[..len], &request, &KEYS.read().await) {
+        Ok(signer) => signer,
+        Err(mut signer) => {
+            warn!("TSIG {} from '{}'", signer.error(), src);
+            request.header.response = true;
+            request.header.rescode = ResultCode::NOTAUTH;
+            request.answers.clear();
+            request.authorities.clear();
+            request.resources.clear();
+            let mut data = to_bytes(&mut request)?;
+            signer.sign(&mut data)?;
+            return Ok(vec![data]);
+        }
+    };
+
+    // The TSIG is not passed on to the upstream servers
+    if let Some(offset) = request.tsig_offset.take() {
+        request.resources.pop();
+        len = offset;
+        let count = request.resources.len() as u16;
+        req.buf[10..12].copy_from_slice(&count.to_be_bytes());
+    }
+    let key = signer.as_ref().map(|signer| signer.key().to_string());
+    let edns = rewrite::udp_size(&req.buf[..len]).is_some();
+    let subnet = rewrite::option(&req.buf[..len], ecs::OPTION_CODE).and_then(Subnet::parse);
+
+    let cookie = rewrite::option(&req.buf[..len], cookie::OPTION_CODE).map(<[u8]>::to_vec);
+    if cookie
+        .as_ref()
+        .is_some_and(|cookie| !cookie::well_formed(cookie))
+    {
+        warn!("Malformed cookie from '{}'", src);
+        request.header.response = true;
+        request.header.rescode = ResultCode::FORMERR;
+        request.resources.clear();
+        return Ok(vec![to_bytes(&mut request)?]);
+    }
+    let verified = match &cookie {
+        Some(cookie) => COOKIES.lock().await.verify(cookie, src.ip()),
+        None => false,
+    };
+    let size = udp_size(&req.buf[..len], verified).await;
+
+    // A server cookie that is not ours gets BADCOOKIE and a new one over UDP,
+    // the client retries with it (RFC 7873 5.2.4)
+    let bad_cookie = !tcp && !verified && cookie.as_deref().is_some_and(cookie::has_server);
+    let mut res = match request.questions.first().map(|q| q.qtype) {
+        _ if bad_cookie => {
+            warn!("Bad cookie from '{}'", src);
+            request.header.response = true;
+            request.answers.clear();
+            request.authorities.clear();
+            request.resources.clear();
+            vec![to_bytes(&mut request)?]
+        }
+        Some(QueryType::AXFR) | Some(QueryType::IXFR) if tcp => {
+            transfer(request, src, key.as_deref()).await?
+        }
+        _ => vec![handle(req, len, request, src, local, key.as_deref(), tcp).await?],
+    };
+
+    // A dropped query is not answered
+    res.retain(|data| !data.is_empty());
+
+    let cookie = match cookie {
+        Some(cookie) => Some(COOKIES.lock().await.respond(&cookie, src.ip())),
+        None => None,
+    };
+    let max_size = *MAX_UDP_SIZE.read().await;
+    let options = |data: &[u8]| client_options(data, edns, cookie.as_deref(), subnet, max_size);
+    // The TSIG record is added after, it must fit in the size of the client too
+    let size = size.saturating_sub(signer.as_ref().map_or(0, Signer::size));
+    for data in res.iter_mut() {
+        *data = match tcp {
+            true => options(data),
+            false => fit(data, size, options),
+        };
+        if bad_cookie {
+            rewrite::set_rcode(data, cookie::BADCOOKIE);
+        }
+    }
+
+    if let Some(signer) = &mut signer {
+        for data in res.iter_mut() {
+            signer.sign(data)?;
+        }
+    }
+    Ok(res)
+}
+
+// The EDNS options of an upstream are replaced with those for the client,
+// the scope of a client subnet is kept
+fn client_options(
+    data: &[u8],
+    edns: bool,
+    cookie: Option<&[u8]>,
+    subnet: Option<Subnet>,
+    size: u16,
+) -> Vec<u8> {
+    if !edns {
+        return rewrite::remove_opt(data);
+    }
+    let scope = rewrite::option(data, ecs::OPTION_CODE)
+        .and_then(Subnet::parse)
+        .map(|answer| answer.scope);
+    let subnet = subnet.zip(scope).map(|(subnet, scope)| {
+        Subnet {
+            scope: scope.min(subnet.cidr.prefix()),
+            ..subnet
+        }
+        .to_bytes()
+    });
+    let data = rewrite::set_option(data, ecs::OPTION_CODE, subnet.as_deref(), size);
+    rewrite::set_option(&data, cookie::OPTION_CODE, cookie, size)
+}
+
+// Larger UDP answers are truncated, the client retries over TCP
+fn fit(data: &[u8], size: usize, options: impl Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+    let data = options(data);
+    if data.len() > size {
+        return options(&rewrite::truncate(&data));
+    }
+    data
+}
+
+// The size the client accepts over UDP, up to the configured maximum
+// A client returning our cookie is not spoofed and gets the size it asks for
+async fn udp_size(req: &[u8], verified: bool) -> usize {
+    let size = rewrite::udp_size(req)
+        .unwrap_or(MIN_UDP_SIZE)
+        .max(MIN_UDP_SIZE);
+    if verified {
+        return size as usize;
+    }
+    size.min(*MAX_UDP_SIZE.read().await) as usize
+}
+
+// AXFR: SOA, records..., SOA
+// IXFR: SOA, (old SOA, deleted records..., new SOA, added records...)..., SOA
+// A signed request must use a key granted for the zone
+async fn transfer(
+    mut request: DnsPacket,
+    src: SocketAddr,
+    key: Option<&str>,
+) -> Result<Vec<Vec<u8>>> {
+    let query = request.questions[0].clone();
+    info!("{} {:?} from '{}'", query.name, query.qtype, src);
+
+    let allowed = granted(&TRANSFER_KEYS.read().await, &query.name, key)
+        || ALLOW_TRANSFER
+            .read()
+            .await
+            .iter()
+            .any(|cidr| cidr.contains(&src.ip()));
+
+    // The serial of the client is in the authority section
+    let client_serial = request.authorities.iter().find_map(|record| match record {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    });
+
+    request.header.response = true;
+    request.authorities.clear();
+    request.resources.clear();
+
+    let hosts = HOSTS.read().await;
+    let zones = ZONES.read().await;
+    let zone = match zones.iter().find(|zone| zone.name == query.name) {
+        Some(zone) if allowed => zone,
+        _ => {
+            warn!("Refused {:?} of '{}' to '{}'", query.qtype, query.name, src);
+            request.header.rescode = ResultCode::REFUSED;
+            return Ok(vec![to_bytes(&mut request)?]);
+        }
+    };
+    request.header.authoritative_answer = true;
+
+    let changes = match query.qtype {
+        QueryType::IXFR => client_serial.and_then(|serial| zone.changes(serial, &hosts)),
+        _ => None,
+    };
+
+    let mut records = Vec::new();
+    match changes {
+        // Already up to date
+        Some(changes) if changes.is_empty() => records.push(zone.soa()),
+        Some(changes) => {
+            records.push(zone.soa());
+            records.extend(changes);
+            records.push(zone.soa());
+        }
+        None => {
+            records = zone.transfer(&hosts);
+            records.push(zone.soa());
+        }
+    }
+
+    messages(request, records)
+}
+
+// Split the records into as many TCP messages as needed, each leaves room for a TSIG record
+fn messages(mut packet: DnsPacket, records: Vec<DnsRecord>) -> Result<Vec<Vec<u8>>> {
+    let mut buffer = BytePacketBuffer::with_size(u16::MAX as usize);
+    let limit = u16::MAX as usize - tsig::MAX_RECORD_SIZE;
+    packet.answers.clear();
+    packet.write(&mut buffer)?;
+    let empty = buffer.pos();
+
+    let mut data = Vec::new();
+    let mut size = empty;
+    for record in records {
+        buffer.pos = 0;
+        let len = record.write(&mut buffer)?;
+        if size + len > limit && !packet.answers.is_empty() {
+            data.push(to_bytes(&mut packet)?);
+            packet.answers.clear();
+            size = empty;
+        }
+        size += len;
+        packet.answers.push(record);
+    }
+    data.push(to_bytes(&mut packet)?);
+
+    Ok(data)
+}
+
+// Up to the size of a TCP message, larger UDP answers are truncated later
+fn to_bytes(packet: &mut DnsPacket) -> Result<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::with_size(u16::MAX as usize);
+    packet.write(&mut buffer)?;
+    Ok(buffer.buf[..buffer.pos()].to_vec())
+}
+
+async fn proxy(buf: &[u8], upstreams: &[SocketAddr]) -> Result<Vec<u8>> {
     let duration = *TIMEOUT.read().await;
 
-    for addr in proxy.iter() {
+    for addr in upstreams {
         let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
 
+        // Queries with EDNS carry our cookie for the upstream
+        let query = match rewrite::udp_size(buf) {
+            Some(size) => {
+                let cookie = COOKIES.lock().await.upstream(*addr);
+                rewrite::set_option(buf, cookie::OPTION_CODE, Some(&cookie), size)
+            }
+            None => buf.to_vec(),
+        };
+
         let data: Result<Vec<u8>> = timeout(duration, async {
-            socket.send_to(buf, addr).await?;
-            let mut res = [0; 512];
-            let len = socket.recv(&mut res).await?;
-            Ok(res[..len].to_vec())
+            socket.send_to(&query, addr).await?;
+            let mut res = [0; MAX_MESSAGE_SIZE];
+            loop {
+                let len = socket.recv(&mut res).await?;
+                let data = &res[..len];
+                if !own_answer(*addr, data).await {
+                    warn!("Wrong cookie in the answer of {}", addr);
+                    continue;
+                }
+                // A truncated answer is asked again over TCP
+                if data.get(2).is_some_and(|flags| flags & 0x02 != 0) {
+                    let data = proxy_tcp(&query, *addr).await?;
+                    if !own_answer(*addr, &data).await {
+                        return Err(Error::other("Wrong cookie in the TCP answer"));
+                    }
+                    return Ok(data);
+                }
+                return Ok(data.to_vec());
+            }
         })
         .await?;
 
@@ -224,62 +989,905 @@ async fn proxy(buf: &[u8]) -> Result<Vec<u8>> {
         }
     }
 
-    Err(Error::new(
-        ErrorKind::Other,
-        "Proxy server failed to proxy request",
-    ))
+    Err(Error::other("Proxy server failed to proxy request"))
 }
 
-async fn get_answer(domain: &str, query: QueryType) -> Option<DnsRecord> {
-    if let Some(ip) = HOSTS.read().await.get(domain) {
-        match query {
-            QueryType::A => {
-                if let IpAddr::V4(addr) = ip {
-                    return Some(DnsRecord::A {
-                        domain: domain.to_string(),
-                        addr: *addr,
-                        ttl: 3600,
-                    });
+// A response without our client cookie is not an answer to our query
+async fn own_answer(addr: SocketAddr, data: &[u8]) -> bool {
+    match rewrite::option(data, cookie::OPTION_CODE) {
+        Some(cookie) => COOKIES.lock().await.learn(addr, cookie),
+        None => true,
+    }
+}
+
+async fn proxy_tcp(query: &[u8], addr: SocketAddr) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_u16(query.len() as u16).await?;
+    stream.write_all(query).await?;
+    let len = stream.read_u16().await?;
+    let mut data = vec![0; len as usize];
+    stream.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+// Resolve a single question through the proxy servers
+// The answer is checked and rewritten as a forwarded one, a rejected answer is REFUSED
+async fn resolve(
+    upstreams: &[SocketAddr],
+    client: IpAddr,
+    id: u16,
+    domain: &str,
+    qtype: QueryType,
+) -> Result<DnsPacket> {
+    let mut packet = question(id, domain, qtype);
+    let data = proxy(&to_bytes(&mut packet)?, upstreams).await?;
+    let data = process(data, packet.clone(), upstreams, client).await?;
+    if data.is_empty() {
+        packet.header.response = true;
+        packet.header.rescode = ResultCode::REFUSED;
+        return Ok(packet);
+    }
+    from_bytes(&data)
+}
+
+// The answer of the proxy servers as it is
+async fn lookup(upstreams: &[SocketAddr], mut packet: DnsPacket) -> Result<DnsPacket> {
+    let data = proxy(&to_bytes(&mut packet)?, upstreams).await?;
+    from_bytes(&data)
+}
+
+fn question(id: u16, domain: &str, qtype: QueryType) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = id;
+    packet.header.recursion_desired = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(domain.to_string(), qtype));
+    packet
+}
+
+fn from_bytes(data: &[u8]) -> Result<DnsPacket> {
+    let mut buffer = BytePacketBuffer::with_size(data.len());
+    buffer.buf.copy_from_slice(data);
+    DnsPacket::from_buffer(&mut buffer)
+}
+
+// The part of an answer that has to be resolved by the proxy server
+enum Next {
+    Done,
+    // Resolve the canonical name
+    Cname(String),
+    // Resolve the target and answer under the owner name
+    Alias { owner: String, target: String },
+}
+
+struct Answer {
+    rescode: ResultCode,
+    authoritative: bool,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    next: Next,
+}
+
+impl Answer {
+    fn new() -> Answer {
+        Answer {
+            rescode: ResultCode::NOERROR,
+            authoritative: false,
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            next: Next::Done,
+        }
+    }
+}
+
+// Follow the local CNAME chain, stop at the first name that is not configured
+// The rules of the view are looked up first
+async fn get_answer(view: Option<&View>, domain: &str, query: QueryType) -> Option<Answer> {
+    let hosts = HOSTS.read().await;
+    let zones = ZONES.read().await;
+    let mut answer = Answer::new();
+    let mut name = domain.to_string();
+
+    answer.authoritative = zone::find(&zones, domain).is_some();
+
+    if query == QueryType::PTR {
+        let host = view
+            .and_then(|view| view.hosts.reverse(domain))
+            .or_else(|| hosts.reverse(domain));
+        if let Some(host) = host {
+            answer.answers.push(DnsRecord::PTR {
+                domain: name,
+                host: host.to_string(),
+                ttl: DEFAULT_TTL,
+            });
+            return Some(answer);
+        }
+    }
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        let records = view
+            .map(|view| view.hosts.get(&name, query))
+            .filter(|records| !records.is_empty())
+            .unwrap_or_else(|| hosts.get(&name, query));
+        match records.first() {
+            Some(Record::Cname(host)) => {
+                answer.answers.push(DnsRecord::CNAME {
+                    domain: name,
+                    host: host.clone(),
+                    ttl: DEFAULT_TTL,
+                });
+                if query == QueryType::CNAME {
+                    break;
                 }
+                name = host.clone();
             }
-            QueryType::AAAA => {
-                if let IpAddr::V6(addr) = ip {
-                    return Some(DnsRecord::AAAA {
-                        domain: domain.to_string(),
+            Some(Record::Alias(host)) => {
+                if query == QueryType::A || query == QueryType::AAAA {
+                    answer.next = Next::Alias {
+                        owner: name,
+                        target: host.clone(),
+                    };
+                    return Some(answer);
+                }
+                break;
+            }
+            Some(Record::Ip(ip)) => {
+                match ip {
+                    IpAddr::V4(addr) => answer.answers.push(DnsRecord::A {
+                        domain: name,
+                        addr: *addr,
+                        ttl: DEFAULT_TTL,
+                    }),
+                    IpAddr::V6(addr) => answer.answers.push(DnsRecord::AAAA {
+                        domain: name,
                         addr: *addr,
-                        ttl: 3600,
-                    });
+                        ttl: DEFAULT_TTL,
+                    }),
+                }
+                break;
+            }
+            Some(Record::Data(_)) => {
+                for record in records {
+                    if let Record::Data(data) = record {
+                        let mut data = data.clone();
+                        data.set_domain(name.clone());
+                        answer.answers.push(data);
+                    }
                 }
+                break;
+            }
+            None => {
+                // Names in a local zone are never forwarded
+                if let Some(zone) = zone::find(&zones, &name) {
+                    let mut records = zone.get(&name, query);
+                    let view_exists = view.is_some_and(|view| view.hosts.exists(&name));
+                    let exists = zone.exists(&name) || hosts.exists(&name) || view_exists;
+                    let mut nxdomain = false;
+                    if records.is_empty() && !exists {
+                        match zone.wildcard(&name, query) {
+                            Some(wildcard) => records = wildcard,
+                            None => nxdomain = true,
+                        }
+                    }
+                    if let Some(DnsRecord::CNAME { host, .. }) = records.first() {
+                        if query != QueryType::CNAME {
+                            let host = host.clone();
+                            answer.answers.extend(records);
+                            name = host;
+                            continue;
+                        }
+                    }
+                    if records.is_empty() {
+                        if nxdomain {
+                            answer.rescode = ResultCode::NXDOMAIN;
+                        }
+                        answer.authorities.push(zone.soa());
+                    }
+                    answer.answers.extend(records);
+                    break;
+                }
+                if answer.answers.is_empty() {
+                    return None;
+                }
+                // The name is configured, but not for this type
+                if hosts.contains(&name) {
+                    break;
+                }
+                answer.next = Next::Cname(name);
+                return Some(answer);
             }
-            _ => {}
         }
     }
-    None
+
+    if answer.answers.is_empty() && answer.authorities.is_empty() {
+        None
+    } else {
+        Some(answer)
+    }
 }
 
-async fn handle(mut req: BytePacketBuffer, len: usize) -> Result<Vec<u8>> {
-    let mut request = DnsPacket::from_buffer(&mut req)?;
+// A primary server announces a new version of a zone
+async fn notify(mut request: DnsPacket, src: SocketAddr, key: Option<&str>) -> Result<Vec<u8>> {
+    let name = match request.questions.first() {
+        Some(q) => q.name.clone(),
+        None => return Err(Error::other("NOTIFY without question")),
+    };
+    info!("{} NOTIFY from '{}'", name, src);
+
+    let mut secondaries = SECONDARIES.write().await;
+    let secondary = secondaries.iter_mut().find(|s| match (&s.key, key) {
+        (Some(expected), Some(key)) => s.name == name && expected == key,
+        _ => s.name == name && s.primary.ip() == src.ip().to_canonical(),
+    });
+
+    request.header.response = true;
+    match secondary {
+        Some(secondary) => {
+            secondary.next_refresh = Instant::now();
+            REFRESH.notify_one();
+            request.header.authoritative_answer = true;
+        }
+        None => {
+            warn!("Refused NOTIFY of '{}' from '{}'", name, src);
+            request.header.rescode = ResultCode::REFUSED;
+        }
+    }
+    request.answers.clear();
+    request.authorities.clear();
+    request.resources.clear();
+    to_bytes(&mut request)
+}
+
+// Whether the key of a signed request is granted for the zone
+fn granted(grants: &[(String, String)], zone: &str, key: Option<&str>) -> bool {
+    key.is_some_and(|key| grants.iter().any(|(z, k)| z == zone && k == key))
+}
+
+// RFC 2136 dynamic update of a local zone
+async fn update(mut req: BytePacketBuffer, src: SocketAddr, key: Option<&str>) -> Result<Vec<u8>> {
+    let message = Update::from_buffer(&mut req)?;
+    let rescode = apply_update(&message, src, key).await;
+    info!("{} UPDATE from '{}' {:?}", message.zone.name, src, rescode);
+    to_bytes(&mut message.response(rescode))
+}
+
+async fn apply_update(message: &Update, src: SocketAddr, key: Option<&str>) -> ResultCode {
+    // The zone section names the SOA of the zone (RFC 2136 3.1.1)
+    if message.zone.qtype != QueryType::SOA {
+        return ResultCode::FORMERR;
+    }
+    if !granted(&UPDATE_KEYS.read().await, &message.zone.name, key)
+        && !ALLOW_UPDATE
+            .read()
+            .await
+            .iter()
+            .any(|cidr| cidr.contains(&src.ip()))
+    {
+        return ResultCode::REFUSED;
+    }
+
+    // Secondary zones are changed on their primary
+    let name = &message.zone.name;
+    if SECONDARIES.read().await.iter().any(|s| &s.name == name) {
+        return ResultCode::NOTAUTH;
+    }
+
+    let mut hosts = HOSTS.write().await;
+    let mut zones = ZONES.write().await;
+    let zone = match zones.iter_mut().find(|zone| &zone.name == name) {
+        Some(zone) => zone,
+        None => return ResultCode::NOTAUTH,
+    };
+
+    let records = zone.transfer(&hosts);
+    if let Err(rescode) = message.check(name, &records) {
+        return rescode;
+    }
+    let changes = match message.changes(name) {
+        Ok(changes) => changes,
+        Err(rescode) => return rescode,
+    };
+
+    // Changed on a copy, the records are answered once they are saved
+    let mut updated = hosts.clone();
+    let changes = changes
+        .into_iter()
+        .filter(|change| change.apply(name, &mut updated))
+        .collect::<Vec<Change>>();
+    if changes.is_empty() {
+        return ResultCode::NOERROR;
+    }
+
+    // The file is restored when a change can not be saved
+    let path = CONFIG_PATH.read().await.clone();
+    let content = match tokio::fs::read(&path).await {
+        Ok(content) => content,
+        Err(err) => {
+            error!("Failed to read {:?} {:?}", path, err);
+            return ResultCode::SERVFAIL;
+        }
+    };
+    for change in &changes {
+        match change.persist(name, &path).await {
+            Ok(true) => {}
+            // Records of imported files come back with the next reload
+            Ok(false) => warn!(
+                "{:?} is not saved, the records are not in {:?}",
+                change, path
+            ),
+            Err(err) => {
+                error!("Failed to save {:?} to {:?} {:?}", change, path, err);
+                if let Err(err) = tokio::fs::write(&path, &content).await {
+                    error!("Failed to restore {:?} {:?}", path, err);
+                }
+                return ResultCode::SERVFAIL;
+            }
+        }
+    }
+    *hosts = updated;
+    zone.changed(records);
+    ResultCode::NOERROR
+}
+
+// Every type of a blocked domain is answered locally, nothing reaches the upstream
+async fn blocked(mut request: DnsPacket, qtype: QueryType) -> Result<Vec<u8>> {
+    request.header.recursion_desired = true;
+    request.header.recursion_available = true;
+    request.header.response = true;
+    request.resources.clear();
+
+    match *BLOCK_MODE.read().await {
+        BlockMode::NxDomain => request.header.rescode = ResultCode::NXDOMAIN,
+        BlockMode::Refused => request.header.rescode = ResultCode::REFUSED,
+        BlockMode::NoData => {}
+        BlockMode::Null => {
+            let domain = request.questions[0].name.clone();
+            match qtype {
+                QueryType::A => request.answers.push(DnsRecord::A {
+                    domain,
+                    addr: Ipv4Addr::UNSPECIFIED,
+                    ttl: DEFAULT_TTL,
+                }),
+                QueryType::AAAA => request.answers.push(DnsRecord::AAAA {
+                    domain,
+                    addr: Ipv6Addr::UNSPECIFIED,
+                    ttl: DEFAULT_TTL,
+                }),
+                _ => {}
+            }
+        }
+    }
+    to_bytes(&mut request)
+}
+
+// Proxied answers are checked against the response policies and for DNS rebinding
+async fn forward(
+    req: &[u8],
+    request: DnsPacket,
+    upstreams: &[SocketAddr],
+    client: IpAddr,
+) -> Result<Vec<u8>> {
+    let data = proxy(req, upstreams).await?;
+    let response = match from_bytes(&data) {
+        Ok(response) => response,
+        Err(_) => return Ok(data),
+    };
+
+    let rpz = RPZ.read().await;
+    let policy = rpz
+        .response_ip(&response.answers)
+        .or_else(|| rpz.nsdname(&response.authorities))
+        .cloned();
+    drop(rpz);
+
+    match policy {
+        Some(policy) if policy.action != Action::Passthru => {
+            info!(
+                "{} answer matched RPZ '{}'",
+                request.questions[0].name, policy.rule
+            );
+            respond_policy(request, policy.action, upstreams, client).await
+        }
+        _ => process(data, request, upstreams, client).await,
+    }
+}
+
+// Every answer of the proxy servers goes through this, also allowed and passed through ones
+// Rebinding protection, DNS64, address mapping and TTL clamping
+async fn process(
+    mut data: Vec<u8>,
+    request: DnsPacket,
+    upstreams: &[SocketAddr],
+    client: IpAddr,
+) -> Result<Vec<u8>> {
+    let response = match from_bytes(&data) {
+        Ok(response) => response,
+        Err(_) => return Ok(data),
+    };
+
+    let name = &request.questions[0].name;
+    if let (Some(mode), Some(ip)) = (
+        *REBIND.read().await,
+        block::private_address(&response.answers),
+    ) {
+        if REBIND_ALLOW.read().await.find(name).is_none() {
+            warn!("{} answered with private address {}", name, ip);
+            return reject(request, mode);
+        }
+    }
 
-    let query = match request.questions.get(0) {
-        Some(q) => q,
-        None => return proxy(&req.buf[..len]).await,
+    let dns64 = DNS64.read().await.clone();
+    if let Some(dns64) = dns64.filter(|dns64| {
+        request.questions[0].qtype == QueryType::AAAA
+            && dns64.serves(&client)
+            && response.header.rescode == ResultCode::NOERROR
+            && !dns64.has_aaaa(&response.answers)
+    }) {
+        if let Some(synthesized) = synthesize(&dns64, &request, &response, upstreams).await? {
+            info!("{} AAAA synthesized", name);
+            data = synthesized;
+        }
+    }
+
+    if rewrite::map_addresses(&mut data, &MAPS.read().await) {
+        info!("{} answer mapped", name);
+    }
+    let (min_ttl, max_ttl) = *TTL.read().await;
+    rewrite::clamp_ttl(&mut data, min_ttl, max_ttl);
+    Ok(data)
+}
+
+// AAAA records made from the A records of the name, None if it has none
+// The TTL is at most that of the negative AAAA answer
+async fn synthesize(
+    dns64: &Dns64,
+    request: &DnsPacket,
+    response: &DnsPacket,
+    upstreams: &[SocketAddr],
+) -> Result<Option<Vec<u8>>> {
+    let name = &request.questions[0].name;
+    let res = lookup(upstreams, question(request.header.id, name, QueryType::A)).await?;
+    let ttl = response.authorities.iter().find_map(|record| match record {
+        DnsRecord::SOA { minimum, ttl, .. } => Some(*minimum.min(ttl)),
+        _ => None,
+    });
+    let answers = dns64.synthesize(res.answers, ttl);
+    if !answers
+        .iter()
+        .any(|record| record.qtype() == QueryType::AAAA)
+    {
+        return Ok(None);
+    }
+
+    let mut packet = request.clone();
+    packet.header.recursion_desired = true;
+    packet.header.recursion_available = true;
+    packet.header.response = true;
+    packet.header.rescode = ResultCode::NOERROR;
+    packet.answers = answers;
+    packet.authorities.clear();
+    packet.resources.clear();
+    to_bytes(&mut packet).map(Some)
+}
+
+async fn filter_aaaa(domain: &str) -> bool {
+    let (all, filter) = &*FILTER_AAAA.read().await;
+    *all || filter.find(domain).is_some()
+}
+
+// Whether the name has an A record, locally or by the proxy
+async fn has_a(
+    view: Option<&View>,
+    domain: &str,
+    upstreams: &[SocketAddr],
+    client: IpAddr,
+    id: u16,
+) -> Result<bool> {
+    let target = match get_answer(view, domain, QueryType::A).await {
+        Some(answer) => match answer.next {
+            Next::Done => {
+                return Ok(answer
+                    .answers
+                    .iter()
+                    .any(|record| record.qtype() == QueryType::A))
+            }
+            Next::Cname(host) => host,
+            Next::Alias { target, .. } => target,
+        },
+        None => domain.to_string(),
     };
+    let res = resolve(upstreams, client, id, &target, QueryType::A).await?;
+    Ok(res
+        .answers
+        .iter()
+        .any(|record| record.qtype() == QueryType::A))
+}
+
+fn nodata(mut request: DnsPacket) -> Result<Vec<u8>> {
+    request.header.recursion_desired = true;
+    request.header.recursion_available = true;
+    request.header.response = true;
+    request.answers.clear();
+    request.authorities.clear();
+    request.resources.clear();
+    to_bytes(&mut request)
+}
 
-    info!("{} {:?}", query.name, query.qtype);
+fn minimal_any(mut request: DnsPacket) -> Result<Vec<u8>> {
+    request.header.recursion_desired = true;
+    request.header.recursion_available = true;
+    request.header.response = true;
+    request.resources.clear();
+    request.answers.push(DnsRecord::HINFO {
+        domain: request.questions[0].name.clone(),
+        cpu: "RFC8482".to_string(),
+        os: String::new(),
+        ttl: DEFAULT_TTL,
+    });
+    to_bytes(&mut request)
+}
+
+// An empty message is not sent
+fn reject(mut request: DnsPacket, mode: RejectMode) -> Result<Vec<u8>> {
+    if mode == RejectMode::Drop {
+        return Ok(Vec::new());
+    }
+    request.header.recursion_desired = true;
+    request.header.recursion_available = true;
+    request.header.response = true;
+    request.header.rescode = ResultCode::REFUSED;
+    request.answers.clear();
+    request.authorities.clear();
+    request.resources.clear();
+    to_bytes(&mut request)
+}
+
+// The answer of a response policy, a CNAME in the local data is resolved by the proxy
+async fn respond_policy(
+    mut request: DnsPacket,
+    action: Action,
+    upstreams: &[SocketAddr],
+    client: IpAddr,
+) -> Result<Vec<u8>> {
+    let query = request.questions[0].clone();
+    request.header.recursion_desired = true;
+    request.header.recursion_available = true;
+    request.header.response = true;
+    request.resources.clear();
+
+    match action {
+        Action::Drop => return Ok(Vec::new()),
+        Action::NxDomain => request.header.rescode = ResultCode::NXDOMAIN,
+        Action::NoData | Action::Passthru => {}
+        Action::Data(_) => {
+            let records = action.records(&query.name, query.qtype);
+            let cname = records.iter().find_map(|record| match record {
+                DnsRecord::CNAME { host, .. } => Some(host.clone()),
+                _ => None,
+            });
+            match cname {
+                Some(host) if query.qtype != QueryType::CNAME => {
+                    let res =
+                        resolve(upstreams, client, request.header.id, &host, query.qtype).await?;
+                    request.header.rescode = res.header.rescode;
+                    request.answers.extend(
+                        records
+                            .into_iter()
+                            .filter(|record| record.qtype() == QueryType::CNAME)
+                            .take(1),
+                    );
+                    request.answers.extend(res.answers);
+                }
+                _ => request.answers.extend(
+                    records
+                        .into_iter()
+                        .filter(|record| record.qtype() == query.qtype),
+                ),
+            }
+        }
+    }
+    to_bytes(&mut request)
+}
+
+async fn handle(
+    mut req: BytePacketBuffer,
+    len: usize,
+    mut request: DnsPacket,
+    src: SocketAddr,
+    local: SocketAddr,
+    key: Option<&str>,
+    tcp: bool,
+) -> Result<Vec<u8>> {
+    match request.header.opcode {
+        NOTIFY_OPCODE => return notify(request, src, key).await,
+        UPDATE_OPCODE => {
+            req.pos = 0;
+            return update(req, src, key).await;
+        }
+        _ => {}
+    }
+
+    let subnet = rewrite::option(&req.buf[..len], ecs::OPTION_CODE).and_then(Subnet::parse);
+    let views = VIEWS.read().await;
+    let trusted = view::trusted_subnet(
+        subnet.as_ref().map(|subnet| &subnet.cidr),
+        &src.ip(),
+        &TRUSTED_ECS.read().await,
+    );
+    let view = views
+        .iter()
+        .find(|view| view.matches(&src.ip(), &local, trusted));
+    let upstreams = match view {
+        Some(view) if !view.proxy.is_empty() => view.proxy.clone(),
+        _ => PROXY.read().await.clone(),
+    };
+
+    // The query sent to the upstreams, with the configured client subnet
+    let outgoing = match *ECS.read().await {
+        Some(ecs) => {
+            let subnet = ecs.subnet(src.ip(), subnet).map(Subnet::to_bytes);
+            rewrite::set_option(
+                &req.buf[..len],
+                ecs::OPTION_CODE,
+                subnet.as_deref(),
+                MAX_MESSAGE_SIZE as u16,
+            )
+        }
+        None => req.buf[..len].to_vec(),
+    };
+    // An answer to a TCP client is not limited by its UDP size
+    let outgoing = if tcp {
+        rewrite::set_udp_size(&outgoing, MAX_MESSAGE_SIZE as u16)
+    } else {
+        outgoing
+    };
+
+    let query = match request.questions.first() {
+        Some(q) => q.clone(),
+        None => return proxy(&outgoing, &upstreams).await,
+    };
+
+    match view {
+        Some(view) => info!("{} {:?} in view '{}'", query.name, query.qtype, view.name),
+        None => info!("{} {:?}", query.name, query.qtype),
+    }
+
+    if DENY_TYPES.read().await.contains(&query.qtype) {
+        info!("{} {:?} denied", query.name, query.qtype);
+        return nodata(request);
+    }
+
+    if let Some(rule) = ALLOW.read().await.find(&query.name) {
+        info!("{} allowed by '{}'", query.name, rule);
+        let data = proxy(&outgoing, &upstreams).await?;
+        return process(data, request, &upstreams, src.ip()).await;
+    }
+
+    let policy = RPZ.read().await.qname(&query.name).cloned();
+    if let Some(policy) = policy {
+        info!("{} matched RPZ '{}'", query.name, policy.rule);
+        if policy.action == Action::Passthru {
+            let data = proxy(&outgoing, &upstreams).await?;
+            return process(data, request, &upstreams, src.ip()).await;
+        }
+        return respond_policy(request, policy.action, &upstreams, src.ip()).await;
+    }
+
+    // RFC 8482, the records of a name are not listed
+    if query.qtype == QueryType::ANY {
+        return minimal_any(request);
+    }
+
+    let rule = match view.and_then(|view| view.block.find(&query.name)) {
+        Some(rule) => Some(rule),
+        None => BLOCK.read().await.find(&query.name),
+    };
+    if let Some(rule) = rule {
+        info!("{} blocked by '{}'", query.name, rule);
+        return blocked(request, query.qtype).await;
+    }
+
+    if query.qtype == QueryType::AAAA && filter_aaaa(&query.name).await {
+        let id = request.header.id;
+        // A failed lookup counts as no A record, the AAAA query is answered as usual
+        let found = match has_a(view, &query.name, &upstreams, src.ip(), id).await {
+            Ok(found) => found,
+            Err(err) => {
+                warn!("{} A lookup failed {:?}", query.name, err);
+                false
+            }
+        };
+        if found {
+            info!("{} AAAA filtered", query.name);
+            return nodata(request);
+        }
+    }
 
     // Whether to proxy
-    let answer = match get_answer(&query.name, query.qtype).await {
-        Some(record) => record,
-        None => return proxy(&req.buf[..len]).await,
+    let mut answer = match get_answer(view, &query.name, query.qtype).await {
+        Some(answer) => answer,
+        None => return forward(&outgoing, request, &upstreams, src.ip()).await,
     };
 
+    match answer.next {
+        Next::Done => {}
+        Next::Cname(host) => {
+            let res = resolve(&upstreams, src.ip(), request.header.id, &host, query.qtype).await?;
+            answer.rescode = res.header.rescode;
+            answer.answers.extend(res.answers);
+        }
+        Next::Alias { owner, target } => {
+            let res = resolve(
+                &upstreams,
+                src.ip(),
+                request.header.id,
+                &target,
+                query.qtype,
+            )
+            .await?;
+            answer.rescode = res.header.rescode;
+            for record in res.answers {
+                match record {
+                    DnsRecord::A { addr, ttl, .. } => answer.answers.push(DnsRecord::A {
+                        domain: owner.clone(),
+                        addr,
+                        ttl,
+                    }),
+                    DnsRecord::AAAA { addr, ttl, .. } => answer.answers.push(DnsRecord::AAAA {
+                        domain: owner.clone(),
+                        addr,
+                        ttl,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+    }
+
     request.header.recursion_desired = true;
     request.header.recursion_available = true;
     request.header.response = true;
-    request.answers.push(answer);
-    let mut res_buffer = BytePacketBuffer::new();
-    request.write(&mut res_buffer)?;
+    request.header.rescode = answer.rescode;
+    request.header.authoritative_answer = answer.authoritative;
+    // The EDNS record of the request can not be written back
+    request.resources.clear();
+    request.answers.extend(answer.answers);
+    request.authorities.extend(answer.authorities);
+    to_bytes(&mut request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use updns::DnsQuestion;
+
+    fn query(qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), qtype));
+        packet
+    }
+
+    #[test]
+    fn minimal_any() {
+        let mut request = query(QueryType::ANY);
+        request.resources.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 60,
+        });
+        let answer = from_bytes(&super::minimal_any(request).unwrap()).unwrap();
+        assert!(answer.header.response);
+        assert!(answer.resources.is_empty());
+        assert_eq!(answer.answers.len(), 1);
+        assert!(matches!(
+            &answer.answers[0],
+            DnsRecord::HINFO { domain, cpu, os, .. }
+                if domain == "example.com" && cpu == "RFC8482" && os.is_empty()
+        ));
+    }
 
-    let data = res_buffer.get_range(0, res_buffer.pos())?;
-    Ok(data.to_vec())
+    #[tokio::test]
+    async fn udp_size() {
+        let request = to_bytes(&mut query(QueryType::A)).unwrap();
+        assert_eq!(super::udp_size(&request, false).await, 512);
+        assert_eq!(super::udp_size(&request, true).await, 512);
+
+        let request = rewrite::set_udp_size(&request, 4096);
+        assert_eq!(super::udp_size(&request, false).await, 1232);
+        assert_eq!(super::udp_size(&request, true).await, 4096);
+        let request = rewrite::set_udp_size(&request, 100);
+        assert_eq!(super::udp_size(&request, true).await, 512);
+    }
+
+    #[test]
+    fn write_records() {
+        let mut packet = query(QueryType::UNKNOWN(65));
+        packet.answers.push(DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65,
+            data: vec![0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2'],
+            ttl: 60,
+        });
+        let answer = from_bytes(&to_bytes(&mut packet).unwrap()).unwrap();
+        assert_eq!(answer.answers, packet.answers);
+
+        // Character strings are limited to 255 bytes
+        let long = "x".repeat(256);
+        let mut packet = query(QueryType::TXT);
+        packet.answers.push(DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: vec![long.clone()],
+            ttl: 60,
+        });
+        assert!(to_bytes(&mut packet).is_err());
+        packet.answers[0] = DnsRecord::HINFO {
+            domain: "example.com".to_string(),
+            cpu: long.clone(),
+            os: String::new(),
+            ttl: 60,
+        };
+        assert!(to_bytes(&mut packet).is_err());
+        packet.answers[0] = DnsRecord::CAA {
+            domain: "example.com".to_string(),
+            flags: 0,
+            tag: long,
+            value: "letsencrypt.org".to_string(),
+            ttl: 60,
+        };
+        assert!(to_bytes(&mut packet).is_err());
+    }
+
+    #[test]
+    fn messages() {
+        let txt = |data: &str| DnsRecord::TXT {
+            domain: "home.arpa".to_string(),
+            data: vec![data.to_string(); 4],
+            ttl: 60,
+        };
+        // A record over 512 bytes
+        let data = super::messages(query(QueryType::AXFR), vec![txt(&"x".repeat(200))]).unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(from_bytes(&data[0]).unwrap().answers.len(), 1);
+
+        let records = vec![txt(&"x".repeat(250)); 200];
+        let data = super::messages(query(QueryType::AXFR), records).unwrap();
+        assert_eq!(data.len(), 4);
+        assert!(data
+            .iter()
+            .all(|data| data.len() <= u16::MAX as usize - tsig::MAX_RECORD_SIZE));
+        let count: usize = data
+            .iter()
+            .map(|data| from_bytes(data).unwrap().answers.len())
+            .sum();
+        assert_eq!(count, 200);
+    }
+
+    #[test]
+    fn fit() {
+        let mut response = query(QueryType::TXT);
+        response.header.response = true;
+        for _ in 0..20 {
+            response.answers.push(DnsRecord::TXT {
+                domain: "example.com".to_string(),
+                data: vec!["x".repeat(100)],
+                ttl: 60,
+            });
+        }
+        let response = to_bytes(&mut response).unwrap();
+        let options = |data: &[u8]| client_options(data, true, None, None, 1232);
+
+        let small = super::fit(&response, 4096, options);
+        assert_eq!(small, options(&response));
+        assert_eq!(small[2] & 0x02, 0);
+
+        let truncated = super::fit(&response, 1232, options);
+        assert!(truncated.len() <= 1232);
+        assert_eq!(truncated[2] & 0x02, 0x02);
+        let answer = from_bytes(&truncated).unwrap();
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.questions.len(), 1);
+    }
 }
@@ -1,10 +1,10 @@
 use regex::{Error, Regex};
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Matcher(MatchMode);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum MatchMode {
     Static(String),
     Wildcard(WildcardMatch),
@@ -34,6 +34,14 @@ impl Matcher {
         Ok(Matcher(MatchMode::Static(raw.to_string())))
     }
 
+    // Plain text matches exactly one domain
+    pub fn as_static(&self) -> Option<&str> {
+        match &self.0 {
+            MatchMode::Static(raw) => Some(raw),
+            _ => None,
+        }
+    }
+
     pub fn is_match(&self, domain: &str) -> bool {
         match &self.0 {
             MatchMode::Static(raw) => raw == domain,
@@ -43,7 +51,7 @@ impl Matcher {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct WildcardMatch {
     chars: Vec<char>,
 }
@@ -57,6 +65,7 @@ impl WildcardMatch {
         Self { chars }
     }
 
+    #[allow(clippy::while_let_on_iterator)]
     fn is_match(&self, text: &str) -> bool {
         let mut chars = text.chars();
         let mut dot = false;
@@ -0,0 +1,256 @@
+// Token buckets for queries of a client and for identical responses (RRL)
+// https://kb.isc.org/docs/aa-00994
+
+use crate::{cidr::Cidr, rewrite::question_end};
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// The oldest buckets are forgotten once there are this many
+const MAX_BUCKETS: usize = 100_000;
+
+// Responses are limited per network
+const RRL_PREFIX: (u8, u8) = (24, 56);
+
+// rate/s, averaged over the window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub rate: f64,
+    pub window: Duration,
+}
+
+impl Limit {
+    fn capacity(&self) -> f64 {
+        (self.rate * self.window.as_secs_f64()).max(1.)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    // Responses dropped since the last slip
+    dropped: u32,
+}
+
+#[derive(Debug)]
+pub struct Buckets<K> {
+    limit: Limit,
+    buckets: HashMap<K, Bucket>,
+    // Keys in the order their buckets were made
+    order: VecDeque<K>,
+    max: usize,
+}
+
+impl<K: Hash + Eq + Clone> Buckets<K> {
+    pub fn new(limit: Limit) -> Buckets<K> {
+        Buckets {
+            limit,
+            buckets: HashMap::new(),
+            order: VecDeque::new(),
+            max: MAX_BUCKETS,
+        }
+    }
+
+    // Takes a token, the bucket is returned when it is empty
+    // A new bucket replaces the oldest one when full, a flood of sources costs no scan
+    fn take(&mut self, key: K, now: Instant) -> Result<(), &mut Bucket> {
+        let capacity = self.limit.capacity();
+        if !self.buckets.contains_key(&key) {
+            if self.buckets.len() >= self.max {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.buckets.remove(&oldest);
+                }
+            }
+            self.order.push_back(key.clone());
+        }
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            last: now,
+            dropped: 0,
+        });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.rate).min(capacity);
+        bucket.last = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(bucket)
+        }
+    }
+}
+
+// The network of the address with the IPv4 or IPv6 prefix length
+fn network(ip: IpAddr, (v4, v6): (u8, u8)) -> Option<Cidr> {
+    let ip = ip.to_canonical();
+    let prefix = if ip.is_ipv4() { v4 } else { v6 };
+    Cidr::from_addr(ip, prefix)
+}
+
+// Queries of a client network, an address by default
+#[derive(Debug)]
+pub struct ClientLimit {
+    buckets: Buckets<Cidr>,
+    prefix: (u8, u8),
+}
+
+impl ClientLimit {
+    pub fn new(limit: Limit, prefix: (u8, u8)) -> ClientLimit {
+        ClientLimit {
+            buckets: Buckets::new(limit),
+            prefix,
+        }
+    }
+
+    pub fn allows(&mut self, ip: IpAddr) -> bool {
+        match network(ip, self.prefix) {
+            Some(network) => self.buckets.take(network, Instant::now()).is_ok(),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    // Answer with TC=1, a real client retries over TCP
+    Slip,
+    Drop,
+}
+
+#[derive(Debug)]
+pub struct Rrl {
+    // Network, question and rcode of the response
+    buckets: Buckets<(Cidr, Vec<u8>, u8)>,
+    // Every nth dropped response slips, 0 never
+    slip: u32,
+}
+
+impl Rrl {
+    pub fn new(limit: Limit, slip: u32) -> Rrl {
+        Rrl {
+            buckets: Buckets::new(limit),
+            slip,
+        }
+    }
+
+    pub fn check(&mut self, ip: IpAddr, response: &[u8]) -> Verdict {
+        let end = match question_end(response) {
+            Some(end) => end,
+            None => return Verdict::Send,
+        };
+        let question = response[12..end].to_ascii_lowercase();
+        let rcode = response[3] & 0x0F;
+
+        let key = match network(ip, RRL_PREFIX) {
+            Some(network) => (network, question, rcode),
+            None => return Verdict::Send,
+        };
+        match self.buckets.take(key, Instant::now()) {
+            Ok(()) => Verdict::Send,
+            Err(bucket) => {
+                bucket.dropped += 1;
+                if self.slip > 0 && bucket.dropped >= self.slip {
+                    bucket.dropped = 0;
+                    Verdict::Slip
+                } else {
+                    Verdict::Drop
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let limit = Limit {
+            rate: 2.,
+            window: Duration::from_secs(2),
+        };
+        let mut buckets = Buckets::new(limit);
+        let now = Instant::now();
+        for _ in 0..4 {
+            assert!(buckets.take("a", now).is_ok());
+        }
+        assert!(buckets.take("a", now).is_err());
+        assert!(buckets.take("b", now).is_ok());
+        assert!(buckets.take("a", now + Duration::from_millis(500)).is_ok());
+        assert!(buckets.take("a", now + Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn bounded() {
+        let limit = Limit {
+            rate: 1.,
+            window: Duration::from_secs(1),
+        };
+        let mut buckets = Buckets::new(limit);
+        buckets.max = 3;
+        let now = Instant::now();
+        assert!(buckets.take(0, now).is_ok());
+        assert!(buckets.take(0, now).is_err());
+        for key in 1..10 {
+            assert!(buckets.take(key, now).is_ok());
+            assert!(buckets.buckets.len() <= 3);
+            assert_eq!(buckets.order.len(), buckets.buckets.len());
+        }
+        // The oldest buckets are gone, the newest are kept
+        assert!(buckets.take(0, now).is_ok());
+        assert!(buckets.take(9, now).is_err());
+
+        let mut buckets = Buckets::new(limit);
+        for key in 0..MAX_BUCKETS + 1000 {
+            assert!(buckets.take(key, now).is_ok());
+        }
+        assert_eq!(buckets.buckets.len(), MAX_BUCKETS);
+        assert_eq!(buckets.order.len(), MAX_BUCKETS);
+    }
+
+    #[test]
+    fn client_prefix() {
+        let limit = Limit {
+            rate: 1.,
+            window: Duration::from_secs(1),
+        };
+        let mut clients = ClientLimit::new(limit, (24, 64));
+        assert!(clients.allows("192.0.2.1".parse().unwrap()));
+        assert!(!clients.allows("192.0.2.2".parse().unwrap()));
+        assert!(!clients.allows("::ffff:192.0.2.3".parse().unwrap()));
+        assert!(clients.allows("192.0.3.1".parse().unwrap()));
+        assert!(clients.allows("2001:db8::1".parse().unwrap()));
+        assert!(!clients.allows("2001:db8::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn slip() {
+        let limit = Limit {
+            rate: 1.,
+            window: Duration::from_secs(1),
+        };
+        let mut rrl = Rrl::new(limit, 2);
+        let response = [
+            0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, 1, b'a', 0, 0, 1, 0, 1, 0xC0, 12, 0, 1,
+            0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1,
+        ];
+        let ip = "192.0.2.1".parse().unwrap();
+        let other = "192.0.2.200".parse().unwrap();
+
+        assert_eq!(rrl.check(ip, &response), Verdict::Send);
+        // Same network
+        assert_eq!(rrl.check(other, &response), Verdict::Drop);
+        assert_eq!(rrl.check(ip, &response), Verdict::Slip);
+        assert_eq!(
+            rrl.check("192.0.3.1".parse().unwrap(), &response),
+            Verdict::Send
+        );
+    }
+}
@@ -0,0 +1,510 @@
+// Changes to proxied answers are made in place,
+// records updns can not parse are passed on as they are
+
+use crate::cidr::Cidr;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::Range,
+};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+// The TTL field of these records holds other data
+const TYPE_OPT: u16 = 41;
+const TYPE_TSIG: u16 = 250;
+
+// 203.0.113.0/24 -> 10.1.0.0/24
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    from: Cidr,
+    to: Cidr,
+}
+
+impl Mapping {
+    // Both prefixes have the same family and length
+    pub fn new(from: Cidr, to: Cidr) -> Option<Mapping> {
+        if from.prefix() != to.prefix() || from.addr().is_ipv4() != to.addr().is_ipv4() {
+            return None;
+        }
+        Some(Mapping { from, to })
+    }
+
+    // The host bits are kept
+    pub fn map(&self, ip: IpAddr) -> Option<IpAddr> {
+        if !self.from.contains(&ip) {
+            return None;
+        }
+        match (ip, self.from.addr(), self.to.addr()) {
+            (IpAddr::V4(ip), IpAddr::V4(from), IpAddr::V4(to)) => {
+                let host = u32::from(ip) ^ u32::from(from);
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from(to) | host)))
+            }
+            (IpAddr::V6(ip), IpAddr::V6(from), IpAddr::V6(to)) => {
+                let host = u128::from(ip) ^ u128::from(from);
+                Some(IpAddr::V6(Ipv6Addr::from(u128::from(to) | host)))
+            }
+            _ => None,
+        }
+    }
+}
+
+// A resource record in a message
+struct RawRecord {
+    start: usize,
+    qtype: u16,
+    class: u16,
+    ttl: usize,
+    data: Range<usize>,
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *message.get(pos)?,
+        *message.get(pos + 1)?,
+    ]))
+}
+
+// The position after a name, compressed or not
+pub fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        if len & 0xC0 == 0xC0 {
+            return Some(pos + 2);
+        }
+        pos += 1;
+        if len == 0 {
+            return Some(pos);
+        }
+        pos += len as usize;
+    }
+}
+
+// Records of all sections, None if the message is malformed
+fn records(message: &[u8]) -> Option<Vec<RawRecord>> {
+    let questions = read_u16(message, 4)?;
+    let count = [6, 8, 10]
+        .iter()
+        .map(|pos| read_u16(message, *pos).map(|n| n as usize))
+        .sum::<Option<usize>>()?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = pos;
+        pos = skip_name(message, pos)?;
+        let qtype = read_u16(message, pos)?;
+        let class = read_u16(message, pos + 2)?;
+        let len = read_u16(message, pos + 8)? as usize;
+        let data = pos + 10..pos + 10 + len;
+        if data.end > message.len() {
+            return None;
+        }
+        records.push(RawRecord {
+            start,
+            qtype,
+            class,
+            ttl: pos + 4,
+            data: data.clone(),
+        });
+        pos = data.end;
+    }
+    Some(records)
+}
+
+// The end of the only question
+pub fn question_end(message: &[u8]) -> Option<usize> {
+    if message.len() < 12 || message[4..6] != [0, 1] {
+        return None;
+    }
+    let end = skip_name(message, 12)? + 4;
+    if end > message.len() {
+        return None;
+    }
+    Some(end)
+}
+
+// The header and the question with TC=1, the client retries over TCP
+pub fn truncate(response: &[u8]) -> Vec<u8> {
+    let end = question_end(response).unwrap_or(12.min(response.len()));
+    let mut message = response[..end].to_vec();
+    if message.len() >= 12 {
+        message[2] |= 0x02;
+        message[6..12].fill(0);
+    }
+    message
+}
+
+// The UDP payload size of the EDNS record (RFC 6891)
+pub fn udp_size(message: &[u8]) -> Option<u16> {
+    records(message)?
+        .iter()
+        .find(|record| record.qtype == TYPE_OPT)
+        .map(|record| record.class)
+}
+
+// Sets the UDP payload size, a message without EDNS gets an OPT record
+pub fn set_udp_size(message: &[u8], size: u16) -> Vec<u8> {
+    let records = match records(message) {
+        Some(records) => records,
+        None => return message.to_vec(),
+    };
+    let mut result = message.to_vec();
+    match records.iter().find(|record| record.qtype == TYPE_OPT) {
+        // The class of the OPT record, before the TTL
+        Some(record) => result[record.ttl - 2..record.ttl].copy_from_slice(&size.to_be_bytes()),
+        None => {
+            result.extend([0, 0, TYPE_OPT as u8]);
+            result.extend(size.to_be_bytes());
+            result.extend([0; 6]);
+            let count = read_u16(message, 10).unwrap_or(0) + 1;
+            result[10..12].copy_from_slice(&count.to_be_bytes());
+        }
+    }
+    result
+}
+
+// EDNS options: code, length, data
+fn options(data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut options = Vec::new();
+    let mut pos = 0;
+    while let (Some(code), Some(len)) = (read_u16(data, pos), read_u16(data, pos + 2)) {
+        let end = pos + 4 + len as usize;
+        if end > data.len() {
+            break;
+        }
+        options.push((code, &data[pos + 4..end]));
+        pos = end;
+    }
+    options
+}
+
+// The data of an EDNS option
+pub fn option(message: &[u8], code: u16) -> Option<&[u8]> {
+    let record = records(message)?
+        .into_iter()
+        .find(|record| record.qtype == TYPE_OPT)?;
+    options(&message[record.data])
+        .into_iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, data)| data)
+}
+
+// Replaces or removes an EDNS option, a message without EDNS gets
+// an OPT record with the UDP size when an option is added
+pub fn set_option(message: &[u8], code: u16, data: Option<&[u8]>, size: u16) -> Vec<u8> {
+    let records = match records(message) {
+        Some(records) => records,
+        None => return message.to_vec(),
+    };
+    // The OPT record up to its data length, and its end
+    let (head, end, mut rdata) = match records.iter().find(|record| record.qtype == TYPE_OPT) {
+        Some(record) => {
+            let mut rdata = Vec::new();
+            for (c, option) in options(&message[record.data.clone()]) {
+                if c != code {
+                    rdata.extend(c.to_be_bytes());
+                    rdata.extend((option.len() as u16).to_be_bytes());
+                    rdata.extend(option);
+                }
+            }
+            (record.start..record.data.start - 2, record.data.end, rdata)
+        }
+        None if data.is_some() => (message.len()..message.len(), message.len(), Vec::new()),
+        None => return message.to_vec(),
+    };
+    if let Some(data) = data {
+        rdata.extend(code.to_be_bytes());
+        rdata.extend((data.len() as u16).to_be_bytes());
+        rdata.extend(data);
+    }
+
+    let mut result = message[..head.start].to_vec();
+    if head.is_empty() {
+        // Root name, type, UDP size, extended rcode, version and flags
+        result.extend([0, 0, TYPE_OPT as u8]);
+        result.extend(size.to_be_bytes());
+        result.extend([0; 4]);
+        let count = read_u16(message, 10).unwrap_or(0) + 1;
+        result[10..12].copy_from_slice(&count.to_be_bytes());
+    } else {
+        result.extend(&message[head]);
+    }
+    result.extend((rdata.len() as u16).to_be_bytes());
+    result.extend(rdata);
+    result.extend(&message[end..]);
+    result
+}
+
+// Removes the OPT record, for clients that did not send one
+pub fn remove_opt(message: &[u8]) -> Vec<u8> {
+    let record = match records(message)
+        .and_then(|records| records.into_iter().find(|record| record.qtype == TYPE_OPT))
+    {
+        Some(record) => record,
+        None => return message.to_vec(),
+    };
+    let mut result = message[..record.start].to_vec();
+    result.extend(&message[record.data.end..]);
+    let count = read_u16(message, 10).unwrap_or(1).saturating_sub(1);
+    result[10..12].copy_from_slice(&count.to_be_bytes());
+    result
+}
+
+// Sets the rcode, the upper 8 bits of an extended one go in the OPT record
+pub fn set_rcode(message: &mut [u8], rcode: u16) -> bool {
+    let records = match records(message) {
+        Some(records) => records,
+        None => return false,
+    };
+    match records.iter().find(|record| record.qtype == TYPE_OPT) {
+        Some(record) => message[record.ttl] = (rcode >> 4) as u8,
+        None if rcode > 0x0F => return false,
+        None => {}
+    }
+    message[3] = (message[3] & 0xF0) | (rcode & 0x0F) as u8;
+    true
+}
+
+// Rewrites the A and AAAA records, returns whether anything was changed
+pub fn map_addresses(message: &mut [u8], mappings: &[Mapping]) -> bool {
+    let records = match records(message) {
+        Some(records) => records,
+        None => return false,
+    };
+    let mut changed = false;
+
+    for record in records {
+        let data = &mut message[record.data];
+        let ip = match (record.qtype, data.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&*data).unwrap())),
+            (TYPE_AAAA, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&*data).unwrap())),
+            _ => continue,
+        };
+        let mapped = match mappings.iter().find_map(|mapping| mapping.map(ip)) {
+            Some(mapped) => mapped,
+            None => continue,
+        };
+        match mapped {
+            IpAddr::V4(v4) => data.copy_from_slice(&v4.octets()),
+            IpAddr::V6(v6) => data.copy_from_slice(&v6.octets()),
+        }
+        changed = true;
+    }
+    changed
+}
+
+// Clamps the TTL of every record, returns whether anything was changed
+pub fn clamp_ttl(message: &mut [u8], min: Option<u32>, max: Option<u32>) -> bool {
+    if min.is_none() && max.is_none() {
+        return false;
+    }
+    let records = match records(message) {
+        Some(records) => records,
+        None => return false,
+    };
+    let mut changed = false;
+
+    for record in records {
+        if record.qtype == TYPE_OPT || record.qtype == TYPE_TSIG {
+            continue;
+        }
+        let field = &mut message[record.ttl..record.ttl + 4];
+        let ttl = u32::from_be_bytes([field[0], field[1], field[2], field[3]]);
+        let mut clamped = ttl.max(min.unwrap_or(0));
+        if let Some(max) = max {
+            clamped = clamped.min(max);
+        }
+        if clamped != ttl {
+            field.copy_from_slice(&clamped.to_be_bytes());
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use updns::{BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType};
+
+    // A CNAME and two A records in the answer, an AAAA record in the additional section
+    fn response() -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "example.com".to_string(),
+            host: "www.example.com".to_string(),
+            ttl: 60,
+        });
+        for ip in ["203.0.113.77", "198.51.100.1"] {
+            packet.answers.push(DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: ip.parse().unwrap(),
+                ttl: 60,
+            });
+        }
+        packet.resources.push(DnsRecord::AAAA {
+            domain: "www.example.com".to_string(),
+            addr: "2001:db8::5".parse().unwrap(),
+            ttl: 60,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos()].to_vec()
+    }
+
+    fn parse(message: &[u8]) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf[..message.len()].copy_from_slice(message);
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn map() {
+        let mapping = |from, to| Mapping::new(Cidr::new(from).unwrap(), Cidr::new(to).unwrap());
+        assert!(mapping("203.0.113.0/24", "10.1.0.0/16").is_none());
+        assert!(mapping("203.0.113.0/24", "fd00::/24").is_none());
+
+        let mappings = [
+            mapping("203.0.113.0/24", "10.1.0.0/24").unwrap(),
+            mapping("2001:db8::/32", "fd00:1::/32").unwrap(),
+        ];
+
+        let mut message = response();
+        assert!(map_addresses(&mut message, &mappings));
+        let packet = parse(&message);
+        assert!(matches!(
+            packet.answers[1],
+            DnsRecord::A { addr, .. } if addr == Ipv4Addr::new(10, 1, 0, 77)
+        ));
+        assert!(matches!(
+            packet.answers[2],
+            DnsRecord::A { addr, .. } if addr == Ipv4Addr::new(198, 51, 100, 1)
+        ));
+        assert!(matches!(
+            packet.resources[0],
+            DnsRecord::AAAA { addr, .. } if addr == "fd00:1::5".parse::<Ipv6Addr>().unwrap()
+        ));
+
+        assert!(!map_addresses(&mut message[..20], &mappings));
+    }
+
+    #[test]
+    fn truncate() {
+        let message = response();
+        let truncated = super::truncate(&message);
+        assert_eq!(truncated.len(), 12 + 17);
+        assert_eq!(truncated[2] & 0x02, 0x02);
+        assert_eq!(&truncated[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parse(&truncated).questions[0].name, "example.com");
+
+        let header = super::truncate(&message[..14]);
+        assert_eq!(header.len(), 12);
+        assert_eq!(header[2] & 0x02, 0x02);
+        assert_eq!(super::truncate(&message[..5]).len(), 5);
+    }
+
+    #[test]
+    fn udp_size() {
+        let message = response();
+        assert_eq!(super::udp_size(&message), None);
+        let mut query = super::truncate(&message);
+        query.extend([0, 0, 41, 0x04, 0xD0, 0, 0, 0, 0, 0, 0]);
+        query[11] = 1;
+        assert_eq!(super::udp_size(&query), Some(1232));
+        assert_eq!(super::udp_size(&query[..20]), None);
+    }
+
+    #[test]
+    fn clamp_ttl() {
+        let mut message = response();
+        assert!(super::clamp_ttl(&mut message, Some(300), None));
+        assert!(!super::clamp_ttl(&mut message, Some(300), Some(3600)));
+        assert!(super::clamp_ttl(&mut message, None, Some(120)));
+        let packet = parse(&message);
+        assert!(packet
+            .answers
+            .iter()
+            .chain(&packet.resources)
+            .all(|record| record.ttl() == 120));
+    }
+
+    #[test]
+    fn options() {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let message = buffer.buf[..buffer.pos()].to_vec();
+
+        assert_eq!(set_option(&message, 10, None, 1232), message);
+        let with = set_option(&message, 10, Some(&[1; 8]), 1232);
+        assert_eq!(super::udp_size(&with), Some(1232));
+        assert_eq!(option(&with, 10), Some(&[1; 8][..]));
+
+        let with = set_option(&with, 8, Some(&[2; 3]), 512);
+        let with = set_option(&with, 10, Some(&[3; 24]), 512);
+        assert_eq!(super::udp_size(&with), Some(1232));
+        assert_eq!(option(&with, 8), Some(&[2; 3][..]));
+        assert_eq!(option(&with, 10), Some(&[3; 24][..]));
+        assert_eq!(&with[10..12], &[0, 1]);
+
+        let without = set_option(&with, 10, None, 1232);
+        assert_eq!(option(&without, 10), None);
+        assert_eq!(without.len(), with.len() - 28);
+        assert_eq!(remove_opt(&without), message);
+    }
+
+    #[test]
+    fn set_udp_size() {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let message = buffer.buf[..buffer.pos()].to_vec();
+
+        let with = super::set_udp_size(&message, 4096);
+        assert_eq!(super::udp_size(&with), Some(4096));
+        assert_eq!(remove_opt(&with), message);
+
+        let with = set_option(&message, 10, Some(&[1; 8]), 1232);
+        let raised = super::set_udp_size(&with, 4096);
+        assert_eq!(super::udp_size(&raised), Some(4096));
+        assert_eq!(option(&raised, 10), Some(&[1; 8][..]));
+        assert_eq!(raised.len(), with.len());
+    }
+
+    #[test]
+    fn set_rcode() {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut message = buffer.buf[..buffer.pos()].to_vec();
+
+        assert!(super::set_rcode(&mut message, 2));
+        assert_eq!(message[3] & 0x0F, 2);
+        // An extended rcode needs an OPT record
+        assert!(!super::set_rcode(&mut message, 23));
+
+        let mut message = set_option(&message, 10, Some(&[1; 8]), 1232);
+        assert!(super::set_rcode(&mut message, 23));
+        assert_eq!(message[3] & 0x0F, 7);
+        // The TTL of the OPT record, before the data length and the cookie
+        let ttl = message.len() - 12 - 2 - 4;
+        assert_eq!(&message[ttl - 4..ttl - 2], &[0, 41]);
+        assert_eq!(message[ttl], 1);
+    }
+}
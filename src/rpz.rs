@@ -0,0 +1,291 @@
+// Response policy zones
+// https://datatracker.ietf.org/doc/draft-vixie-dnsop-dns-rpz/
+
+use crate::cidr::Cidr;
+use logs::warn;
+use std::{collections::HashMap, net::IpAddr};
+use updns::{DnsRecord, QueryType};
+
+const IP_TRIGGER: &str = ".rpz-ip";
+const NSDNAME_TRIGGER: &str = ".rpz-nsdname";
+
+// CNAME targets with a special meaning
+const NODATA: &str = "*";
+const PASSTHRU: &str = "rpz-passthru";
+const DROP: &str = "rpz-drop";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    // CNAME .
+    NxDomain,
+    // CNAME *.
+    NoData,
+    // CNAME rpz-passthru.
+    Passthru,
+    // CNAME rpz-drop.
+    Drop,
+    // Any other records, answered under the queried name
+    Data(Vec<DnsRecord>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    // The owner name in the policy zone
+    pub rule: String,
+    pub action: Action,
+}
+
+// The first zone with a matching trigger decides
+#[derive(Debug, Default)]
+pub struct Rpz {
+    names: HashMap<String, Policy>,
+    // *.example.com, keyed by example.com
+    wildcards: HashMap<String, Policy>,
+    ips: Vec<(Cidr, Policy)>,
+    nsdnames: HashMap<String, Policy>,
+}
+
+// 32.1.2.0.192  ->  192.0.2.1/32
+// 128.1.zz.db8.2001  ->  2001:db8::1/128
+fn ip_trigger(name: &str) -> Option<Cidr> {
+    let mut labels = name.split('.').collect::<Vec<&str>>();
+    labels.reverse();
+    let prefix = labels.pop()?;
+    let v4 = labels.len() == 4 && labels.iter().all(|label| label.parse::<u8>().is_ok());
+    let addr = if v4 {
+        labels.join(".")
+    } else {
+        let addr = labels
+            .iter()
+            .map(|label| if *label == "zz" { "" } else { label })
+            .collect::<Vec<&str>>()
+            .join(":");
+        // 2001:db8:: or ::1
+        match addr.as_str() {
+            _ if addr.ends_with(':') => addr + ":",
+            _ if addr.starts_with(':') => ":".to_string() + &addr,
+            _ => addr,
+        }
+    };
+    Cidr::new(&format!("{}/{}", addr, prefix))
+}
+
+impl Rpz {
+    pub fn new() -> Rpz {
+        Rpz::default()
+    }
+
+    pub fn from_records(origin: &str, records: Vec<DnsRecord>) -> Rpz {
+        let mut rpz = Rpz::new();
+        let suffix = format!(".{}", origin);
+        let mut policies: Vec<Policy> = Vec::new();
+
+        for record in records {
+            // SOA and NS of the policy zone
+            let rule = match record.domain().strip_suffix(&suffix) {
+                Some(rule) => rule.to_string(),
+                None => continue,
+            };
+            let action = match &record {
+                DnsRecord::CNAME { host, .. } => match host.as_str() {
+                    "" => Action::NxDomain,
+                    NODATA => Action::NoData,
+                    PASSTHRU => Action::Passthru,
+                    DROP => Action::Drop,
+                    _ => Action::Data(vec![record]),
+                },
+                _ => Action::Data(vec![record]),
+            };
+
+            // Local data of a trigger may have several records
+            match policies.iter_mut().find(|policy| policy.rule == rule) {
+                Some(Policy {
+                    action: Action::Data(records),
+                    ..
+                }) => {
+                    if let Action::Data(data) = action {
+                        records.extend(data);
+                    }
+                }
+                Some(_) => {}
+                None => policies.push(Policy { rule, action }),
+            }
+        }
+
+        for policy in policies {
+            rpz.push(policy);
+        }
+        rpz
+    }
+
+    fn push(&mut self, policy: Policy) {
+        let rule = policy.rule.clone();
+        if let Some(name) = rule.strip_suffix(IP_TRIGGER) {
+            match ip_trigger(name) {
+                Some(cidr) => self.ips.push((cidr, policy)),
+                None => warn!("Cannot parse RPZ IP trigger '{}'", rule),
+            }
+        } else if let Some(name) = rule.strip_suffix(NSDNAME_TRIGGER) {
+            self.nsdnames.entry(name.to_string()).or_insert(policy);
+        } else if rule.starts_with("rpz-") || rule.contains(".rpz-") {
+            warn!("Unsupported RPZ trigger '{}'", rule);
+        } else if let Some(name) = rule.strip_prefix("*.") {
+            self.wildcards.entry(name.to_string()).or_insert(policy);
+        } else {
+            self.names.entry(rule).or_insert(policy);
+        }
+    }
+
+    pub fn extend(&mut self, other: Rpz) {
+        for (name, policy) in other.names {
+            self.names.entry(name).or_insert(policy);
+        }
+        for (name, policy) in other.wildcards {
+            self.wildcards.entry(name).or_insert(policy);
+        }
+        for (name, policy) in other.nsdnames {
+            self.nsdnames.entry(name).or_insert(policy);
+        }
+        self.ips.extend(other.ips);
+    }
+
+    // An exact name is preferred to a wildcard, a closer wildcard to a wider one
+    pub fn qname(&self, domain: &str) -> Option<&Policy> {
+        let domain = domain.to_ascii_lowercase();
+        if let Some(policy) = self.names.get(&domain) {
+            return Some(policy);
+        }
+        let mut name = domain.as_str();
+        while let Some((_, parent)) = name.split_once('.') {
+            if let Some(policy) = self.wildcards.get(parent) {
+                return Some(policy);
+            }
+            name = parent;
+        }
+        None
+    }
+
+    // Addresses in the answer section, the longest matching prefix decides
+    pub fn response_ip(&self, answers: &[DnsRecord]) -> Option<&Policy> {
+        if self.ips.is_empty() {
+            return None;
+        }
+        let mut best: Option<&(Cidr, Policy)> = None;
+        let ips = answers.iter().filter_map(|record| match record {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        });
+        for ip in ips {
+            for entry in self.ips.iter().filter(|(cidr, _)| cidr.contains(&ip)) {
+                if best.is_none_or(|(cidr, _)| entry.0.prefix() > cidr.prefix()) {
+                    best = Some(entry);
+                }
+            }
+        }
+        best.map(|(_, policy)| policy)
+    }
+
+    // Name servers in the authority section, upstream resolvers often leave it empty
+    pub fn nsdname(&self, authorities: &[DnsRecord]) -> Option<&Policy> {
+        if self.nsdnames.is_empty() {
+            return None;
+        }
+        authorities.iter().find_map(|record| match record {
+            DnsRecord::NS { host, .. } => self.nsdnames.get(&host.to_ascii_lowercase()),
+            _ => None,
+        })
+    }
+}
+
+impl Action {
+    // Local data of the queried type under the queried name, or a CNAME to follow
+    pub fn records(&self, domain: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        let records = match self {
+            Action::Data(records) => records,
+            _ => return Vec::new(),
+        };
+        records
+            .iter()
+            .filter(|record| record.qtype() == qtype || record.qtype() == QueryType::CNAME)
+            .map(|record| {
+                let mut record = record.clone();
+                record.set_domain(domain.to_string());
+                record
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zonefile;
+
+    #[test]
+    fn triggers() {
+        let content = "$TTL 300
+@                       SOA  ns hostmaster 1 3600 600 86400 300
+                        NS   ns
+bad.example             CNAME .
+*.bad.example           CNAME *.
+ok.bad.example          CNAME rpz-passthru.
+drop.example            CNAME rpz-drop.
+local.example           A    10.0.0.1
+local.example           A    10.0.0.2
+local.example           TXT  \"policy\"
+24.0.2.0.192.rpz-ip     CNAME .
+32.9.2.0.192.rpz-ip     CNAME rpz-passthru.
+64.zz.db8.2001.rpz-ip   CNAME *.
+ns.evil.rpz-nsdname     CNAME .
+";
+        let (records, invalid) = zonefile::parse("rpz.test", content);
+        assert!(invalid.is_empty(), "{:?}", invalid);
+        let rpz = Rpz::from_records("rpz.test", records);
+
+        let action = |name| rpz.qname(name).map(|policy| policy.action.clone());
+        assert_eq!(action("bad.example"), Some(Action::NxDomain));
+        assert_eq!(action("www.bad.example"), Some(Action::NoData));
+        assert_eq!(action("ok.bad.example"), Some(Action::Passthru));
+        assert_eq!(action("drop.example"), Some(Action::Drop));
+        assert_eq!(action("example"), None);
+        assert_eq!(
+            rpz.qname("local.example")
+                .unwrap()
+                .action
+                .records("local.example", QueryType::A)
+                .len(),
+            2
+        );
+
+        let a = |ip: &str| DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: ip.parse().unwrap(),
+            ttl: 60,
+        };
+        assert_eq!(
+            rpz.response_ip(&[a("192.0.2.8")]).unwrap().rule,
+            "24.0.2.0.192.rpz-ip"
+        );
+        assert_eq!(
+            rpz.response_ip(&[a("192.0.2.8"), a("192.0.2.9")])
+                .unwrap()
+                .action,
+            Action::Passthru
+        );
+        assert!(rpz.response_ip(&[a("192.0.3.9")]).is_none());
+        let aaaa = DnsRecord::AAAA {
+            domain: "example.com".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 60,
+        };
+        assert_eq!(rpz.response_ip(&[aaaa]).unwrap().action, Action::NoData);
+
+        let ns = DnsRecord::NS {
+            domain: "evil".to_string(),
+            host: "ns.evil".to_string(),
+            ttl: 60,
+        };
+        assert_eq!(rpz.nsdname(&[ns]).unwrap().action, Action::NxDomain);
+    }
+}
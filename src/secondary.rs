@@ -0,0 +1,164 @@
+use crate::tsig::{Key, Signer};
+use std::{net::SocketAddr, time::Instant};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Error, Result},
+    net::{TcpStream, UdpSocket},
+    time::{timeout, Duration},
+};
+use updns::{BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+
+// A zone transfer is given up beyond these
+const MAX_TRANSFER_SIZE: usize = 64 * 1024 * 1024;
+const MAX_TRANSFER_TIME: Duration = Duration::from_secs(600);
+
+// A zone transferred from a primary server
+#[derive(Debug)]
+pub struct Secondary {
+    pub name: String,
+    pub primary: SocketAddr,
+    // The TSIG key of the queries to the primary and of its NOTIFY
+    pub key: Option<String>,
+    // When the primary is asked for a new version
+    pub next_refresh: Instant,
+    // The zone expires when the primary is unreachable for too long
+    pub last_refresh: Option<Instant>,
+}
+
+impl Secondary {
+    pub fn new(name: String, primary: SocketAddr, key: Option<String>) -> Secondary {
+        Secondary {
+            name,
+            primary,
+            key,
+            next_refresh: Instant::now(),
+            last_refresh: None,
+        }
+    }
+}
+
+// A query with a random ID, and the signer that checks the responses
+fn query(name: &str, qtype: QueryType, key: Option<&Key>) -> Result<(Vec<u8>, Option<Signer>)> {
+    let mut id = [0; 2];
+    getrandom::getrandom(&mut id).map_err(Error::other)?;
+    let mut packet = DnsPacket::new();
+    packet.header.id = u16::from_be_bytes(id);
+    packet
+        .questions
+        .push(DnsQuestion::new(name.to_string(), qtype));
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer)?;
+    let mut message = buffer.buf[..buffer.pos()].to_vec();
+    let signer = match key {
+        Some(key) => {
+            let mut signer = Signer::new(key, packet.header.id);
+            signer.sign(&mut message)?;
+            Some(signer)
+        }
+        None => None,
+    };
+    Ok((message, signer))
+}
+
+// A response to the request, signed when the request is
+fn check(message: &[u8], packet: &DnsPacket, id: u16, signer: &mut Option<Signer>) -> Result<()> {
+    if packet.header.id != id {
+        return Err(Error::other("Primary server answered another query"));
+    }
+    if let Some(signer) = signer {
+        signer.check(message, packet)?;
+    }
+    match packet.header.rescode {
+        ResultCode::NOERROR => Ok(()),
+        code => Err(Error::other(format!("Primary server answered {:?}", code))),
+    }
+}
+
+// The serial of the zone on the primary server
+pub async fn serial(
+    name: &str,
+    primary: SocketAddr,
+    key: Option<&Key>,
+    duration: Duration,
+) -> Result<u32> {
+    let bind: SocketAddr = match primary {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    // Datagrams from other addresses are not received
+    socket.connect(primary).await?;
+    let (req, mut signer) = query(name, QueryType::SOA, key)?;
+    let id = u16::from_be_bytes([req[0], req[1]]);
+
+    let mut res = BytePacketBuffer::new();
+    let len = timeout(duration, async {
+        socket.send(&req).await?;
+        socket.recv(&mut res.buf).await
+    })
+    .await??;
+
+    let packet = DnsPacket::from_buffer(&mut res)?;
+    check(&res.buf[..len], &packet, id, &mut signer)?;
+    packet
+        .answers
+        .iter()
+        .find_map(|record| match record {
+            DnsRecord::SOA { serial, .. } => Some(*serial),
+            _ => None,
+        })
+        .ok_or_else(|| Error::other("Primary server has no SOA record"))
+}
+
+// AXFR: SOA, records..., SOA
+// Limited in size and time, duration is the timeout of a single read
+pub async fn transfer(
+    name: &str,
+    primary: SocketAddr,
+    key: Option<&Key>,
+    duration: Duration,
+) -> Result<Vec<DnsRecord>> {
+    timeout(MAX_TRANSFER_TIME, receive(name, primary, key, duration))
+        .await
+        .map_err(|_| Error::other("Zone transfer takes too long"))?
+}
+
+async fn receive(
+    name: &str,
+    primary: SocketAddr,
+    key: Option<&Key>,
+    duration: Duration,
+) -> Result<Vec<DnsRecord>> {
+    let mut stream = timeout(duration, TcpStream::connect(primary)).await??;
+    let (req, mut signer) = query(name, QueryType::AXFR, key)?;
+    let id = u16::from_be_bytes([req[0], req[1]]);
+    stream.write_u16(req.len() as u16).await?;
+    stream.write_all(&req).await?;
+
+    let mut records = Vec::new();
+    let mut size = 0;
+    loop {
+        let len = timeout(duration, stream.read_u16()).await?? as usize;
+        size += len;
+        if size > MAX_TRANSFER_SIZE {
+            return Err(Error::other("Zone transfer is too large"));
+        }
+        let mut buffer = BytePacketBuffer::with_size(len);
+        timeout(duration, stream.read_exact(&mut buffer.buf)).await??;
+
+        let packet = DnsPacket::from_buffer(&mut buffer)?;
+        check(&buffer.buf, &packet, id, &mut signer)?;
+
+        for record in packet.answers {
+            let soa = record.qtype() == QueryType::SOA;
+            if records.is_empty() && !soa {
+                return Err(Error::other("Zone transfer does not start with SOA"));
+            }
+            // The transfer ends with the SOA record again
+            if soa && !records.is_empty() {
+                return Ok(records);
+            }
+            records.push(record);
+        }
+    }
+}
@@ -0,0 +1,526 @@
+// RFC 8945 transaction signatures
+// https://www.rfc-editor.org/rfc/rfc8945
+
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+use std::{
+    result,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{Error, Result};
+use updns::{BytePacketBuffer, DnsPacket, DnsRecord};
+
+// Allowed difference between the clocks of the client and the server
+const FUDGE: u16 = 300;
+
+// Room for a TSIG record, one with a name of 255 bytes and a SHA-512 MAC fits
+pub const MAX_RECORD_SIZE: usize = 512;
+
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+// key name secret
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub name: String,
+    secret: Vec<u8>,
+}
+
+impl Key {
+    pub fn new(name: String, secret: Vec<u8>) -> Key {
+        Key { name, secret }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "hmac-sha256" => Some(Algorithm::Sha256),
+            "hmac-sha512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    // HMAC takes keys of any size
+    fn mac(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Algorithm::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    fn verify(&self, secret: &[u8], data: &[u8], tag: &[u8]) -> bool {
+        match self {
+            Algorithm::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+                mac.update(data);
+                mac.verify_slice(tag).is_ok()
+            }
+            Algorithm::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).unwrap();
+                mac.update(data);
+                mac.verify_slice(tag).is_ok()
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Uncompressed and lowercase
+fn wire(name: &str, data: &mut Vec<u8>) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        data.push(label.len() as u8);
+        data.extend(label.to_ascii_lowercase().as_bytes());
+    }
+    data.push(0);
+}
+
+// 48 bits
+fn time(time: u64, data: &mut Vec<u8>) {
+    data.extend(&time.to_be_bytes()[2..]);
+}
+
+fn mac_field(mac: &[u8], data: &mut Vec<u8>) {
+    data.extend((mac.len() as u16).to_be_bytes());
+    data.extend(mac);
+}
+
+// The message as it was before the TSIG was added
+fn unsigned(message: &[u8], offset: usize, original_id: u16) -> Vec<u8> {
+    let mut data = message[..offset].to_vec();
+    data[0..2].copy_from_slice(&original_id.to_be_bytes());
+    let count = u16::from_be_bytes([data[10], data[11]]).saturating_sub(1);
+    data[10..12].copy_from_slice(&count.to_be_bytes());
+    data
+}
+
+// Signs the responses to a verified request, or reports why the verification failed
+#[derive(Debug)]
+pub struct Signer {
+    key: String,
+    secret: Vec<u8>,
+    algorithm_name: String,
+    algorithm: Option<Algorithm>,
+    original_id: u16,
+    error: u16,
+    // The MAC of the request, then of the previous response
+    mac: Vec<u8>,
+    signed: bool,
+    // A response to our request was checked, later ones only have the timers signed
+    answered: bool,
+}
+
+impl Signer {
+    // Signs a request of this server, e.g. the SOA query and AXFR of a secondary zone
+    pub fn new(key: &Key, id: u16) -> Signer {
+        Signer {
+            key: key.name.clone(),
+            secret: key.secret.clone(),
+            algorithm_name: "hmac-sha256".to_string(),
+            algorithm: Some(Algorithm::Sha256),
+            original_id: id,
+            error: 0,
+            mac: Vec::new(),
+            signed: false,
+            answered: false,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // Ok(None) if the request is not signed
+    pub fn verify(
+        message: &[u8],
+        packet: &DnsPacket,
+        keys: &[Key],
+    ) -> result::Result<Option<Signer>, Signer> {
+        let (offset, tsig) = match (packet.tsig_offset, packet.tsig()) {
+            (Some(offset), Some(tsig)) => (offset, tsig),
+            _ => return Ok(None),
+        };
+        let (algorithm_name, time_signed, fudge, mac, original_id, error, other) = match tsig {
+            DnsRecord::TSIG {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+                ..
+            } => (
+                algorithm,
+                *time_signed,
+                *fudge,
+                mac,
+                *original_id,
+                *error,
+                other,
+            ),
+            _ => return Ok(None),
+        };
+
+        let mut signer = Signer {
+            key: tsig.domain().to_string(),
+            secret: Vec::new(),
+            algorithm_name: algorithm_name.clone(),
+            algorithm: Algorithm::from_name(algorithm_name),
+            original_id,
+            error: 0,
+            mac: Vec::new(),
+            signed: false,
+            answered: false,
+        };
+
+        let key = keys.iter().find(|key| key.name == signer.key);
+        let (key, algorithm) = match (key, signer.algorithm) {
+            (Some(key), Some(algorithm)) => (key, algorithm),
+            _ => {
+                signer.error = BADKEY;
+                return Err(signer);
+            }
+        };
+
+        let mut data = unsigned(message, offset, original_id);
+        wire(&signer.key, &mut data);
+        data.extend(255u16.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        wire(algorithm_name, &mut data);
+        time(time_signed, &mut data);
+        data.extend(fudge.to_be_bytes());
+        data.extend(error.to_be_bytes());
+        mac_field(other, &mut data);
+
+        if !algorithm.verify(&key.secret, &data, mac) {
+            signer.error = BADSIG;
+            return Err(signer);
+        }
+
+        signer.secret = key.secret.clone();
+        signer.mac = mac.clone();
+        if now().abs_diff(time_signed) > fudge as u64 {
+            signer.error = BADTIME;
+            return Err(signer);
+        }
+        Ok(Some(signer))
+    }
+
+    // Checks a response to the request signed by this signer
+    // Every message of a transfer must be signed, each MAC covers the previous one
+    pub fn check(&mut self, message: &[u8], packet: &DnsPacket) -> Result<()> {
+        let (offset, tsig) = match (packet.tsig_offset, packet.tsig()) {
+            (Some(offset), Some(tsig)) => (offset, tsig),
+            _ => return Err(Error::other("Response is not signed")),
+        };
+        let (algorithm_name, time_signed, fudge, mac, original_id, error, other) = match tsig {
+            DnsRecord::TSIG {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+                ..
+            } => (
+                algorithm,
+                *time_signed,
+                *fudge,
+                mac,
+                *original_id,
+                *error,
+                other,
+            ),
+            _ => return Err(Error::other("Response is not signed")),
+        };
+        let algorithm = match self.algorithm {
+            Some(algorithm)
+                if tsig.domain().eq_ignore_ascii_case(&self.key)
+                    && algorithm_name.eq_ignore_ascii_case(&self.algorithm_name)
+                    && original_id == self.original_id =>
+            {
+                algorithm
+            }
+            _ => return Err(Error::other("Response is signed with another key")),
+        };
+        if error != 0 {
+            return Err(Error::other(format!("Response has TSIG error {}", error)));
+        }
+
+        let mut data = Vec::new();
+        mac_field(&self.mac, &mut data);
+        data.extend(unsigned(message, offset, original_id));
+        if !self.answered {
+            wire(&self.key, &mut data);
+            data.extend(255u16.to_be_bytes());
+            data.extend(0u32.to_be_bytes());
+            wire(algorithm_name, &mut data);
+        }
+        time(time_signed, &mut data);
+        data.extend(fudge.to_be_bytes());
+        if !self.answered {
+            data.extend(error.to_be_bytes());
+            mac_field(other, &mut data);
+        }
+
+        if !algorithm.verify(&self.secret, &data, mac) {
+            return Err(Error::other("Response has a wrong MAC"));
+        }
+        if now().abs_diff(time_signed) > fudge as u64 {
+            return Err(Error::other("Response is signed at a wrong time"));
+        }
+        self.mac = mac.clone();
+        self.answered = true;
+        Ok(())
+    }
+
+    // The size of the TSIG record sign appends
+    pub fn size(&self) -> usize {
+        let name = |name: &str| {
+            let mut data = Vec::new();
+            wire(name, &mut data);
+            data.len()
+        };
+        let mac = match self.algorithm {
+            _ if self.error == BADKEY || self.error == BADSIG => 0,
+            Some(Algorithm::Sha256) => 32,
+            Some(Algorithm::Sha512) => 64,
+            None => 0,
+        };
+        let other = if self.error == BADTIME { 6 } else { 0 };
+        // Type, class, TTL and length, then time, fudge, MAC length, ID, error and other length
+        name(&self.key) + 10 + name(&self.algorithm_name) + 16 + mac + other
+    }
+
+    pub fn error(&self) -> &str {
+        match self.error {
+            BADSIG => "BADSIG",
+            BADKEY => "BADKEY",
+            BADTIME => "BADTIME",
+            _ => "NOERROR",
+        }
+    }
+
+    // Appends the TSIG record to the message
+    // Every message of a transfer is signed, the first one with all the TSIG variables
+    pub fn sign(&mut self, message: &mut Vec<u8>) -> Result<()> {
+        let time_signed = now();
+        let mut other = Vec::new();
+        if self.error == BADTIME {
+            time(time_signed, &mut other);
+        }
+
+        // A request with an unknown key or a wrong MAC gets an unsigned answer
+        let mac = match self.algorithm {
+            Some(algorithm) if self.error != BADKEY && self.error != BADSIG => {
+                // A request has no MAC before it
+                let mut data = Vec::new();
+                if !self.mac.is_empty() {
+                    mac_field(&self.mac, &mut data);
+                }
+                data.extend(message.as_slice());
+                if !self.signed {
+                    wire(&self.key, &mut data);
+                    data.extend(255u16.to_be_bytes());
+                    data.extend(0u32.to_be_bytes());
+                    wire(&self.algorithm_name, &mut data);
+                }
+                time(time_signed, &mut data);
+                data.extend(FUDGE.to_be_bytes());
+                if !self.signed {
+                    data.extend(self.error.to_be_bytes());
+                    mac_field(&other, &mut data);
+                }
+                algorithm.mac(&self.secret, &data)
+            }
+            _ => Vec::new(),
+        };
+        self.mac = mac.clone();
+        self.signed = true;
+
+        let record = DnsRecord::TSIG {
+            domain: self.key.clone(),
+            algorithm: self.algorithm_name.clone(),
+            time_signed,
+            fudge: FUDGE,
+            mac,
+            original_id: self.original_id,
+            error: self.error,
+            other,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer)?;
+        message.extend(&buffer.buf[..buffer.pos()]);
+
+        let count = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&count.to_be_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use updns::{DnsQuestion, QueryType};
+
+    fn request(key: &Key, time_signed: u64) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 7;
+        packet
+            .questions
+            .push(DnsQuestion::new("home.arpa".to_string(), QueryType::SOA));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut message = buffer.buf[..buffer.pos()].to_vec();
+
+        let mut data = message.clone();
+        wire(&key.name, &mut data);
+        data.extend(255u16.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        wire("hmac-sha256", &mut data);
+        time(time_signed, &mut data);
+        data.extend(FUDGE.to_be_bytes());
+        data.extend(0u16.to_be_bytes());
+        data.extend(0u16.to_be_bytes());
+
+        let record = DnsRecord::TSIG {
+            domain: key.name.clone(),
+            algorithm: "hmac-sha256".to_string(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Algorithm::Sha256.mac(&key.secret, &data),
+            original_id: 7,
+            error: 0,
+            other: Vec::new(),
+        };
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        message.extend(&buffer.buf[..buffer.pos()]);
+        message[11] = 1;
+        message
+    }
+
+    fn packet(message: &[u8]) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::with_size(message.len());
+        buffer.buf.copy_from_slice(message);
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    fn verify(message: &[u8], keys: &[Key]) -> result::Result<Option<Signer>, Signer> {
+        Signer::verify(message, &packet(message), keys)
+    }
+
+    #[test]
+    fn verify_request() {
+        let key = Key::new("ddns.key".to_string(), b"secret".to_vec());
+        let keys = vec![key.clone()];
+
+        let message = request(&key, now());
+        let mut signer = verify(&message, &keys).unwrap().unwrap();
+
+        // Only the header and the question
+        let mut response = message[..27].to_vec();
+        response[11] = 0;
+        let len = response.len();
+        let size = signer.size();
+        signer.sign(&mut response).unwrap();
+        assert_eq!(response.len(), len + size);
+        assert!(matches!(
+            packet(&response).tsig(),
+            Some(DnsRecord::TSIG { mac, .. }) if mac.len() == 32
+        ));
+
+        // Wrong MAC
+        let mut message = request(&key, now());
+        let len = message.len();
+        message[len - 10] ^= 1;
+        assert_eq!(verify(&message, &keys).unwrap_err().error, BADSIG);
+
+        let other = Key::new("other.key".to_string(), b"secret".to_vec());
+        let message = request(&other, now());
+        assert_eq!(verify(&message, &keys).unwrap_err().error, BADKEY);
+
+        let message = request(&key, now() - 3600);
+        assert_eq!(verify(&message, &keys).unwrap_err().error, BADTIME);
+
+        // A request signed by this server
+        let mut message = request(&key, now())[..27].to_vec();
+        message[11] = 0;
+        Signer::new(&key, 7).sign(&mut message).unwrap();
+        assert_eq!(verify(&message, &keys).unwrap().unwrap().key(), "ddns.key");
+    }
+
+    #[test]
+    fn check_response() {
+        let key = Key::new("ddns.key".to_string(), b"secret".to_vec());
+        let keys = vec![key.clone()];
+
+        // A signed request and the two signed messages of its transfer
+        let exchange = || {
+            let mut request = request(&key, now())[..27].to_vec();
+            request[11] = 0;
+            let mut client = Signer::new(&key, 7);
+            client.sign(&mut request).unwrap();
+            let mut server = verify(&request, &keys).unwrap().unwrap();
+            let responses: Vec<Vec<u8>> = (0..2)
+                .map(|_| {
+                    let mut response = request[..27].to_vec();
+                    response[2] |= 0x80;
+                    response[11] = 0;
+                    server.sign(&mut response).unwrap();
+                    response
+                })
+                .collect();
+            (client, responses)
+        };
+
+        let (mut client, responses) = exchange();
+        for response in &responses {
+            client.check(response, &packet(response)).unwrap();
+        }
+
+        // Out of order
+        let (mut client, responses) = exchange();
+        assert!(client.check(&responses[1], &packet(&responses[1])).is_err());
+
+        // Unsigned
+        let (mut client, responses) = exchange();
+        let mut unsigned = responses[0][..27].to_vec();
+        unsigned[11] = 0;
+        assert!(client.check(&unsigned, &packet(&unsigned)).is_err());
+
+        // Wrong MAC
+        let mut wrong = responses[0].clone();
+        let len = wrong.len();
+        wrong[len - 10] ^= 1;
+        assert!(client.check(&wrong, &packet(&wrong)).is_err());
+
+        // Answer to another request
+        let mut other = Signer::new(&key, 8);
+        other.mac = client.mac.clone();
+        assert!(other.check(&responses[0], &packet(&responses[0])).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+// RFC 2136 dynamic update
+// https://www.rfc-editor.org/rfc/rfc2136
+
+use crate::{
+    config::{Hosts, Parser, Record},
+    matcher::Matcher,
+    zone::is_subdomain,
+    DEFAULT_TTL,
+};
+use std::{collections::HashMap, path::Path, result};
+use tokio::io::{Error, Result};
+use updns::{
+    BytePacketBuffer, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode,
+};
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+// Zone, prerequisite and update sections of an UPDATE message
+#[derive(Debug)]
+pub struct Update {
+    pub header: DnsHeader,
+    pub zone: DnsQuestion,
+    prerequisites: Vec<(DnsRecord, u16)>,
+    updates: Vec<(DnsRecord, u16)>,
+}
+
+// A change to the plain text hosts
+#[derive(Debug)]
+pub enum Change {
+    Add(String, Record),
+    // All records of the name, the records of a type, or a single record
+    Delete(String, Option<QueryType>, Option<DnsRecord>),
+}
+
+// Records without data only name a type in prerequisites and deletions
+fn empty(record: &DnsRecord) -> bool {
+    matches!(record, DnsRecord::UNKNOWN { data, .. } if data.is_empty())
+}
+
+// Types that can be written to the config file
+fn persistable(record: &DnsRecord) -> bool {
+    matches!(
+        record,
+        DnsRecord::A { .. }
+            | DnsRecord::AAAA { .. }
+            | DnsRecord::NS { .. }
+            | DnsRecord::CNAME { .. }
+            | DnsRecord::SOA { .. }
+            | DnsRecord::PTR { .. }
+            | DnsRecord::MX { .. }
+            | DnsRecord::TXT { .. }
+            | DnsRecord::SRV { .. }
+            | DnsRecord::CAA { .. }
+    )
+}
+
+// Records are compared without their TTL
+fn same(a: &DnsRecord, b: &DnsRecord) -> bool {
+    normalize(a) == normalize(b)
+}
+
+fn normalize(record: &DnsRecord) -> DnsRecord {
+    let mut record = record.clone();
+    record.set_ttl(0);
+    record
+}
+
+impl Update {
+    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<Update> {
+        let mut header = DnsHeader::new();
+        header.read(buffer)?;
+        if header.questions != 1 {
+            return Err(Error::other("UPDATE must name exactly one zone"));
+        }
+
+        let mut zone = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+        zone.read(buffer)?;
+
+        let mut prerequisites = Vec::new();
+        for _ in 0..header.answers {
+            prerequisites.push(DnsRecord::read_with_class(buffer)?);
+        }
+        let mut updates = Vec::new();
+        for _ in 0..header.authoritative_entries {
+            updates.push(DnsRecord::read_with_class(buffer)?);
+        }
+
+        Ok(Update {
+            header,
+            zone,
+            prerequisites,
+            updates,
+        })
+    }
+
+    pub fn response(&self, rescode: ResultCode) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header = self.header.clone();
+        packet.header.response = true;
+        packet.header.rescode = rescode;
+        packet.questions.push(self.zone.clone());
+        packet
+    }
+
+    // Prerequisites are checked against all records of the zone
+    // https://www.rfc-editor.org/rfc/rfc2136#section-3.2
+    pub fn check(&self, zone: &str, records: &[DnsRecord]) -> result::Result<(), ResultCode> {
+        let mut rrsets: HashMap<(&str, QueryType), Vec<DnsRecord>> = HashMap::new();
+
+        for (record, class) in &self.prerequisites {
+            let name = record.domain();
+            let qtype = record.qtype();
+            if record.ttl() != 0 {
+                return Err(ResultCode::FORMERR);
+            }
+            if !is_subdomain(name, zone) {
+                return Err(ResultCode::NOTZONE);
+            }
+
+            let mut found = records.iter().filter(|r| r.domain() == name);
+            match (*class, qtype) {
+                // Name is in use
+                (CLASS_ANY, QueryType::ANY) if empty(record) => {
+                    if found.next().is_none() {
+                        return Err(ResultCode::NXDOMAIN);
+                    }
+                }
+                // RRset exists
+                (CLASS_ANY, _) if empty(record) => {
+                    if !found.any(|r| r.qtype() == qtype) {
+                        return Err(ResultCode::NXRRSET);
+                    }
+                }
+                // Name is not in use
+                (CLASS_NONE, QueryType::ANY) if empty(record) => {
+                    if found.next().is_some() {
+                        return Err(ResultCode::YXDOMAIN);
+                    }
+                }
+                // RRset does not exist
+                (CLASS_NONE, _) if empty(record) => {
+                    if found.any(|r| r.qtype() == qtype) {
+                        return Err(ResultCode::YXRRSET);
+                    }
+                }
+                // RRset exists with exactly these records
+                (CLASS_IN, _) if !empty(record) => {
+                    rrsets
+                        .entry((name, qtype))
+                        .or_default()
+                        .push(normalize(record));
+                }
+                _ => return Err(ResultCode::FORMERR),
+            }
+        }
+
+        for ((name, qtype), mut expected) in rrsets {
+            let mut actual = records
+                .iter()
+                .filter(|r| r.domain() == name && r.qtype() == qtype)
+                .map(normalize)
+                .collect::<Vec<DnsRecord>>();
+            expected.sort();
+            expected.dedup();
+            actual.sort();
+            actual.dedup();
+            if expected != actual {
+                return Err(ResultCode::NXRRSET);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The update section is checked as a whole before anything is changed
+    // https://www.rfc-editor.org/rfc/rfc2136#section-3.4
+    pub fn changes(&self, zone: &str) -> result::Result<Vec<Change>, ResultCode> {
+        let mut changes = Vec::new();
+
+        for (record, class) in &self.updates {
+            let name = record.domain().to_string();
+            let qtype = record.qtype();
+            if !is_subdomain(&name, zone) {
+                return Err(ResultCode::NOTZONE);
+            }
+            match qtype {
+                QueryType::AXFR | QueryType::IXFR => return Err(ResultCode::FORMERR),
+                QueryType::ANY if *class != CLASS_ANY => return Err(ResultCode::FORMERR),
+                _ => {}
+            }
+
+            match *class {
+                CLASS_IN if !empty(record) => {
+                    if !persistable(record) {
+                        return Err(ResultCode::NOTIMP);
+                    }
+                    // The serial of the zone is managed by the server
+                    if qtype == QueryType::SOA {
+                        continue;
+                    }
+                    // A new CNAME replaces the old one
+                    if qtype == QueryType::CNAME {
+                        changes.push(Change::Delete(name.clone(), Some(qtype), None));
+                    }
+                    // Records in the config file have no TTL of their own
+                    let mut record = record.clone();
+                    record.set_ttl(DEFAULT_TTL);
+                    changes.push(Change::Add(name, Record::from(record)));
+                }
+                CLASS_ANY if empty(record) && record.ttl() == 0 => {
+                    let qtype = Some(qtype).filter(|qtype| *qtype != QueryType::ANY);
+                    changes.push(Change::Delete(name, qtype, None));
+                }
+                CLASS_NONE if !empty(record) && record.ttl() == 0 => {
+                    changes.push(Change::Delete(name, Some(qtype), Some(record.clone())));
+                }
+                _ => return Err(ResultCode::FORMERR),
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+impl Change {
+    // Whether a configured record is removed by the change
+    pub fn deletes(&self, zone: &str, matcher: &Matcher, record: &Record) -> bool {
+        let (name, qtype, data) = match self {
+            Change::Delete(name, qtype, data) => (name, qtype, data),
+            Change::Add(..) => return false,
+        };
+        if matcher.as_static() != Some(name.as_str()) {
+            return false;
+        }
+        let record = match record.to_record(name.clone()) {
+            Some(record) => record,
+            None => return false,
+        };
+
+        // The name servers of the zone are not removed with the other records
+        if name == zone && record.qtype() == QueryType::NS && data.is_none() {
+            return false;
+        }
+        qtype.is_none_or(|qtype| qtype == record.qtype())
+            && data.as_ref().is_none_or(|data| same(data, &record))
+    }
+
+    // Returns whether the hosts were changed
+    pub fn apply(&self, zone: &str, hosts: &mut Hosts) -> bool {
+        let (name, record) = match self {
+            Change::Add(name, record) => (name, record),
+            Change::Delete(..) => {
+                let len = hosts.iter().len();
+                hosts.retain(|matcher, record| !self.deletes(zone, matcher, record));
+                return hosts.iter().len() != len;
+            }
+        };
+
+        let new = match record.to_record(name.clone()) {
+            Some(new) => new,
+            None => return false,
+        };
+        let existing = hosts
+            .iter()
+            .filter(|(matcher, _)| matcher.as_static() == Some(name.as_str()))
+            .filter_map(|(_, record)| record.to_record(name.clone()))
+            .collect::<Vec<DnsRecord>>();
+
+        if existing.iter().any(|record| same(record, &new)) {
+            return false;
+        }
+        // A CNAME can not be added to a name with other records, and the other way around
+        let cname = new.qtype() == QueryType::CNAME;
+        if existing
+            .iter()
+            .any(|record| (record.qtype() == QueryType::CNAME) != cname)
+        {
+            return false;
+        }
+
+        match Matcher::new(name) {
+            Ok(matcher) => {
+                hosts.push((matcher, record.clone()));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Saved the same way as records added from the command line
+    // Returns false when the deleted records are not in the file, e.g. imported
+    pub async fn persist<P: AsRef<Path>>(&self, zone: &str, path: P) -> Result<bool> {
+        let mut parser = Parser::new(path).await?;
+        match self {
+            Change::Add(name, record) => {
+                parser.add(name, &record.to_string()).await?;
+                Ok(true)
+            }
+            Change::Delete(..) => {
+                let removed = parser
+                    .remove(|matcher, record| self.deletes(zone, matcher, record))
+                    .await?;
+                Ok(removed > 0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prerequisites_and_changes() {
+        let a = |name: &str, ip: &str, ttl| DnsRecord::A {
+            domain: name.to_string(),
+            addr: ip.parse().unwrap(),
+            ttl,
+        };
+        let typed = |name: &str, qtype: QueryType| DnsRecord::UNKNOWN {
+            domain: name.to_string(),
+            qtype: qtype.to_num(),
+            data: Vec::new(),
+            ttl: 0,
+        };
+        let message = |prerequisites, updates| Update {
+            header: DnsHeader::new(),
+            zone: DnsQuestion::new("home.arpa".to_string(), QueryType::SOA),
+            prerequisites,
+            updates,
+        };
+        let records = vec![a("nas.home.arpa", "10.0.0.7", 3600)];
+
+        let update = message(
+            vec![
+                (typed("nas.home.arpa", QueryType::A), CLASS_ANY),
+                (typed("ci.home.arpa", QueryType::ANY), CLASS_NONE),
+                (a("nas.home.arpa", "10.0.0.7", 0), CLASS_IN),
+            ],
+            vec![
+                (typed("nas.home.arpa", QueryType::ANY), CLASS_ANY),
+                (a("ci.home.arpa", "10.0.0.8", 300), CLASS_IN),
+            ],
+        );
+        assert_eq!(update.check("home.arpa", &records), Ok(()));
+
+        let mut hosts = Hosts::new();
+        hosts.push((
+            Matcher::new("nas.home.arpa").unwrap(),
+            Record::Ip("10.0.0.7".parse().unwrap()),
+        ));
+        let changes = update.changes("home.arpa").unwrap();
+        assert!(changes
+            .iter()
+            .all(|change| change.apply("home.arpa", &mut hosts)));
+        let hosts = hosts
+            .iter()
+            .map(|(matcher, record)| format!("{} {}", matcher.as_static().unwrap(), record))
+            .collect::<Vec<String>>();
+        assert_eq!(hosts, vec!["ci.home.arpa 10.0.0.8"]);
+
+        let check = |prerequisite| message(vec![prerequisite], vec![]).check("home.arpa", &records);
+        assert_eq!(
+            check((typed("nas.home.arpa", QueryType::ANY), CLASS_NONE)),
+            Err(ResultCode::YXDOMAIN)
+        );
+        assert_eq!(
+            check((typed("ci.home.arpa", QueryType::ANY), CLASS_ANY)),
+            Err(ResultCode::NXDOMAIN)
+        );
+        assert_eq!(
+            check((a("nas.home.arpa", "10.0.0.8", 0), CLASS_IN)),
+            Err(ResultCode::NXRRSET)
+        );
+        assert_eq!(
+            check((typed("example.com", QueryType::A), CLASS_ANY)),
+            Err(ResultCode::NOTZONE)
+        );
+
+        // HINFO records can not be written to the config file
+        let hinfo = DnsRecord::HINFO {
+            domain: "nas.home.arpa".to_string(),
+            cpu: "arm".to_string(),
+            os: "linux".to_string(),
+            ttl: 300,
+        };
+        assert!(matches!(
+            message(vec![], vec![(hinfo, CLASS_IN)]).changes("home.arpa"),
+            Err(ResultCode::NOTIMP)
+        ));
+    }
+}
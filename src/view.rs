@@ -0,0 +1,107 @@
+use crate::{block::Blocklist, cidr::Cidr, config::Hosts};
+use std::net::{IpAddr, SocketAddr};
+
+// Split horizon, the first view matching a query answers it
+// Its rules are looked up before the global ones, its upstreams replace the global ones
+#[derive(Debug)]
+pub struct View {
+    pub name: String,
+    // Client addresses
+    pub sources: Vec<Cidr>,
+    // Client subnets (ECS) sent by trusted forwarding resolvers
+    pub subnets: Vec<Cidr>,
+    // Addresses the queries are received on
+    pub bind: Vec<SocketAddr>,
+    pub proxy: Vec<SocketAddr>,
+    pub hosts: Hosts,
+    pub block: Blocklist,
+}
+
+impl View {
+    pub fn new(name: String) -> View {
+        View {
+            name,
+            sources: Vec::new(),
+            subnets: Vec::new(),
+            bind: Vec::new(),
+            proxy: Vec::new(),
+            hosts: Hosts::new(),
+            block: Blocklist::new(),
+        }
+    }
+
+    // A view without sources, subnets and bind addresses matches every query
+    pub fn matches(&self, src: &IpAddr, local: &SocketAddr, subnet: Option<&Cidr>) -> bool {
+        (self.sources.is_empty() && self.subnets.is_empty() && self.bind.is_empty())
+            || self.sources.iter().any(|cidr| cidr.contains(src))
+            || subnet.is_some_and(|subnet| {
+                self.subnets
+                    .iter()
+                    .any(|cidr| cidr.prefix() <= subnet.prefix() && cidr.contains(&subnet.addr()))
+            })
+            || self.bind.contains(local)
+    }
+}
+
+// The client subnet of a query is only used from a trusted forwarder,
+// any client could claim a subnet to pick a view otherwise
+pub fn trusted_subnet<'a>(
+    subnet: Option<&'a Cidr>,
+    src: &IpAddr,
+    trusted: &[Cidr],
+) -> Option<&'a Cidr> {
+    subnet.filter(|_| trusted.iter().any(|cidr| cidr.contains(src)))
+}
+
+// A wildcard address takes the port on every address of its family, [::] also on IPv4
+// Binding a specific address to the same port next to it fails
+pub fn overlaps(wildcard: &SocketAddr, addr: &SocketAddr) -> bool {
+    wildcard.ip().is_unspecified()
+        && !addr.ip().is_unspecified()
+        && wildcard.port() == addr.port()
+        && (wildcard.is_ipv6() || addr.is_ipv4())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        let local = "0.0.0.0:53".parse().unwrap();
+        let guest = "192.168.50.1:53".parse().unwrap();
+
+        let mut view = View::new("vpn".to_string());
+        assert!(view.matches(&"8.8.8.8".parse().unwrap(), &local, None));
+
+        view.sources.push(Cidr::new("10.8.0.0/24").unwrap());
+        view.bind.push(guest);
+        assert!(view.matches(&"10.8.0.2".parse().unwrap(), &local, None));
+        assert!(view.matches(&"8.8.8.8".parse().unwrap(), &guest, None));
+        assert!(!view.matches(&"8.8.8.8".parse().unwrap(), &local, None));
+
+        view.subnets.push(Cidr::new("198.51.100.0/24").unwrap());
+        let office = Cidr::new("198.51.100.0/26").unwrap();
+        let wide = Cidr::new("198.51.0.0/16").unwrap();
+        assert!(view.matches(&"8.8.8.8".parse().unwrap(), &local, Some(&office)));
+        assert!(!view.matches(&"8.8.8.8".parse().unwrap(), &local, Some(&wide)));
+
+        let trusted = [Cidr::new("10.0.0.53/32").unwrap()];
+        let forwarder = "10.0.0.53".parse().unwrap();
+        let client = "8.8.8.8".parse().unwrap();
+        assert_eq!(
+            trusted_subnet(Some(&office), &forwarder, &trusted),
+            Some(&office)
+        );
+        assert_eq!(trusted_subnet(Some(&office), &client, &trusted), None);
+        assert_eq!(trusted_subnet(None, &forwarder, &trusted), None);
+        assert_eq!(trusted_subnet(Some(&office), &forwarder, &[]), None);
+
+        let addr = |text: &str| text.parse::<SocketAddr>().unwrap();
+        assert!(overlaps(&local, &guest));
+        assert!(overlaps(&addr("[::]:53"), &guest));
+        assert!(!overlaps(&local, &addr("[fd00::1]:53")));
+        assert!(!overlaps(&local, &addr("192.168.50.1:5353")));
+        assert!(!overlaps(&guest, &local));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{config::Hosts, DEFAULT_TTL};
+use logs::warn;
+use std::time::{SystemTime, UNIX_EPOCH};
+use updns::{DnsRecord, QueryType};
+
+const DEFAULT_NAMESERVER: &str = "localhost";
+// Versions kept for incremental transfers
+const MAX_HISTORY: usize = 16;
+const REFRESH: u32 = 3600;
+const RETRY: u32 = 600;
+const EXPIRE: u32 = 86400;
+const MINIMUM: u32 = 300;
+
+// A domain suffix that is answered locally and never forwarded
+#[derive(Debug)]
+pub struct Zone {
+    pub name: String,
+    // The SOA record is always the first one
+    records: Vec<DnsRecord>,
+    // Previous versions of the transferred records, oldest first
+    history: Vec<Vec<DnsRecord>>,
+}
+
+impl Zone {
+    pub fn new(name: String, nameserver: Option<String>) -> Zone {
+        let nameserver = nameserver.unwrap_or_else(|| DEFAULT_NAMESERVER.to_string());
+        // Secondaries must see a larger serial after a restart
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(1);
+        let soa = DnsRecord::SOA {
+            domain: name.clone(),
+            mname: nameserver.clone(),
+            rname: format!("hostmaster.{}", name),
+            serial,
+            refresh: REFRESH,
+            retry: RETRY,
+            expire: EXPIRE,
+            minimum: MINIMUM,
+            ttl: DEFAULT_TTL,
+        };
+        let ns = DnsRecord::NS {
+            domain: name.clone(),
+            host: nameserver,
+            ttl: DEFAULT_TTL,
+        };
+        Zone {
+            name,
+            records: vec![soa, ns],
+            history: Vec::new(),
+        }
+    }
+
+    // Zone data loaded from a file, it must have an SOA record at the apex
+    pub fn from_records(name: String, mut records: Vec<DnsRecord>) -> Option<Zone> {
+        let i = records
+            .iter()
+            .position(|r| r.qtype() == QueryType::SOA && r.domain() == name)?;
+        let soa = records.remove(i);
+        records.retain(|r| r.qtype() != QueryType::SOA);
+        // Records of unknown types are not served, their data can not be checked
+        let len = records.len();
+        records.retain(|r| !matches!(r, DnsRecord::UNKNOWN { .. }));
+        if records.len() < len {
+            warn!(
+                "Zone '{}' dropped {} records of unsupported types",
+                name,
+                len - records.len()
+            );
+        }
+        records.insert(0, soa);
+        Some(Zone {
+            name,
+            records,
+            history: Vec::new(),
+        })
+    }
+
+    pub fn contains(&self, domain: &str) -> bool {
+        is_subdomain(domain, &self.name)
+    }
+
+    pub fn soa(&self) -> DnsRecord {
+        self.records[0].clone()
+    }
+
+    pub fn serial(&self) -> u32 {
+        match self.records[0] {
+            DnsRecord::SOA { serial, .. } => serial,
+            _ => unreachable!(),
+        }
+    }
+
+    // refresh, retry, expire
+    pub fn timers(&self) -> (u32, u32, u32) {
+        match self.records[0] {
+            DnsRecord::SOA {
+                refresh,
+                retry,
+                expire,
+                ..
+            } => (refresh, retry, expire),
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_serial(&mut self, value: u32) {
+        if let DnsRecord::SOA { ref mut serial, .. } = self.records[0] {
+            *serial = value;
+        }
+    }
+
+    // Replaces the previous version of the zone after a reload
+    // The serial is bumped and the previous records are kept for IXFR
+    pub fn update(&mut self, old: &Zone, old_hosts: &Hosts) {
+        let serial = self.serial().max(old.serial().wrapping_add(1));
+        self.set_serial(serial);
+
+        self.history = old.history.clone();
+        self.keep(old.transfer(old_hosts));
+    }
+
+    // Bumps the serial after the records were changed in place
+    pub fn changed(&mut self, previous: Vec<DnsRecord>) {
+        self.set_serial(self.serial().wrapping_add(1));
+        self.keep(previous);
+    }
+
+    fn keep(&mut self, previous: Vec<DnsRecord>) {
+        self.history.push(previous);
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+    }
+
+    // All records of the zone, the plain text hosts in the zone are included
+    // SOA, records...
+    pub fn transfer(&self, hosts: &Hosts) -> Vec<DnsRecord> {
+        let mut records = self.records.clone();
+
+        for (matcher, record) in hosts.iter() {
+            let domain = match matcher.as_static() {
+                Some(domain) if self.contains(domain) => domain.to_string(),
+                _ => continue,
+            };
+            let record = match record.to_record(domain) {
+                Some(record) => record,
+                None => continue,
+            };
+            if !records.contains(&record) {
+                records.push(record);
+            }
+        }
+
+        records
+    }
+
+    // Differences since the serial as IXFR sequences, or None if that version is unknown
+    // old SOA, deleted records..., new SOA, added records...
+    pub fn changes(&self, serial: u32, hosts: &Hosts) -> Option<Vec<DnsRecord>> {
+        if serial == self.serial() {
+            return Some(Vec::new());
+        }
+
+        let start = self.history.iter().position(
+            |records| matches!(records[0], DnsRecord::SOA { serial: s, .. } if s == serial),
+        )?;
+
+        let mut versions = self.history[start..].to_vec();
+        versions.push(self.transfer(hosts));
+
+        let mut changes = Vec::new();
+        for pair in versions.windows(2) {
+            let (old, new) = (&pair[0], &pair[1]);
+            changes.push(old[0].clone());
+            changes.extend(old[1..].iter().filter(|r| !new.contains(r)).cloned());
+            changes.push(new[0].clone());
+            changes.extend(new[1..].iter().filter(|r| !old.contains(r)).cloned());
+        }
+        Some(changes)
+    }
+
+    // Records of the type, or the CNAME of the domain
+    pub fn get(&self, domain: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        let records: Vec<DnsRecord> = self
+            .records
+            .iter()
+            .filter(|r| r.domain() == domain && r.qtype() == qtype)
+            .cloned()
+            .collect();
+        if !records.is_empty() {
+            return records;
+        }
+
+        self.records
+            .iter()
+            .filter(|r| r.domain() == domain && r.qtype() == QueryType::CNAME)
+            .take(1)
+            .cloned()
+            .collect()
+    }
+
+    // Whether the domain has any records, or is the parent of a domain with records
+    pub fn exists(&self, domain: &str) -> bool {
+        self.records
+            .iter()
+            .any(|r| is_subdomain(r.domain(), domain))
+    }
+
+    // Records of the wildcard at the closest encloser renamed to the domain (RFC 4592)
+    // None if no wildcard applies, the domain does not exist then
+    // *.home.arpa answers for nas.home.arpa and a.nas.home.arpa
+    pub fn wildcard(&self, domain: &str, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        let mut name = domain;
+        while let Some((_, parent)) = name.split_once('.') {
+            if !self.contains(parent) {
+                break;
+            }
+            if self.exists(parent) {
+                let source = format!("*.{}", parent);
+                if !self.records.iter().any(|r| r.domain() == source) {
+                    return None;
+                }
+                let records = self
+                    .get(&source, qtype)
+                    .into_iter()
+                    .map(|mut record| {
+                        record.set_domain(domain.to_string());
+                        record
+                    })
+                    .collect();
+                return Some(records);
+            }
+            name = parent;
+        }
+        None
+    }
+}
+
+// nas.home.arpa is a subdomain of home.arpa, and home.arpa of itself
+pub fn is_subdomain(domain: &str, parent: &str) -> bool {
+    match domain.strip_suffix(parent) {
+        Some("") => true,
+        Some(rest) => parent.is_empty() || rest.ends_with('.'),
+        None => false,
+    }
+}
+
+// The most specific zone that contains the domain
+pub fn find<'a>(zones: &'a [Zone], domain: &str) -> Option<&'a Zone> {
+    zones
+        .iter()
+        .filter(|zone| zone.contains(domain))
+        .max_by_key(|zone| zone.name.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_zone() {
+        let zones = vec![
+            Zone::new("arpa".to_string(), None),
+            Zone::new("home.arpa".to_string(), None),
+        ];
+        assert_eq!(find(&zones, "home.arpa").unwrap().name, "home.arpa");
+        assert_eq!(find(&zones, "nas.home.arpa").unwrap().name, "home.arpa");
+        assert_eq!(find(&zones, "myhome.arpa").unwrap().name, "arpa");
+        assert!(find(&zones, "example.com").is_none());
+    }
+
+    #[test]
+    fn zone_changes() {
+        let soa = |serial| DnsRecord::SOA {
+            domain: "home.arpa".to_string(),
+            mname: "localhost".to_string(),
+            rname: "hostmaster.home.arpa".to_string(),
+            serial,
+            refresh: REFRESH,
+            retry: RETRY,
+            expire: EXPIRE,
+            minimum: MINIMUM,
+            ttl: DEFAULT_TTL,
+        };
+        let a = |name: &str, ip: &str| DnsRecord::A {
+            domain: name.to_string(),
+            addr: ip.parse().unwrap(),
+            ttl: DEFAULT_TTL,
+        };
+
+        // A DNSKEY record is dropped
+        let dnskey = DnsRecord::UNKNOWN {
+            domain: "home.arpa".to_string(),
+            qtype: 48,
+            data: vec![0; 68],
+            ttl: DEFAULT_TTL,
+        };
+
+        let hosts = Hosts::new();
+        let v1 = Zone::from_records(
+            "home.arpa".to_string(),
+            vec![soa(1), dnskey, a("a.home.arpa", "10.0.0.1")],
+        );
+        let mut v2 = Zone::from_records(
+            "home.arpa".to_string(),
+            vec![soa(1), a("b.home.arpa", "10.0.0.2")],
+        );
+        let (v1, v2) = (v1.unwrap(), v2.as_mut().unwrap());
+        v2.update(&v1, &hosts);
+
+        assert_eq!(v2.serial(), 2);
+        assert_eq!(
+            v2.changes(1, &hosts).unwrap(),
+            vec![
+                soa(1),
+                a("a.home.arpa", "10.0.0.1"),
+                soa(2),
+                a("b.home.arpa", "10.0.0.2")
+            ]
+        );
+        assert!(v2.changes(2, &hosts).unwrap().is_empty());
+        assert!(v2.changes(0, &hosts).is_none());
+    }
+
+    #[test]
+    fn wildcard() {
+        let a = |name: &str, ip: &str| DnsRecord::A {
+            domain: name.to_string(),
+            addr: ip.parse().unwrap(),
+            ttl: DEFAULT_TTL,
+        };
+        let mut zone = Zone::new("home.arpa".to_string(), None);
+        zone.records.push(a("*.home.arpa", "10.0.0.1"));
+        zone.records.push(a("nas.home.arpa", "10.0.0.2"));
+        zone.records.push(a("a.b.home.arpa", "10.0.0.3"));
+
+        assert_eq!(
+            zone.wildcard("tv.home.arpa", QueryType::A),
+            Some(vec![a("tv.home.arpa", "10.0.0.1")])
+        );
+        assert_eq!(
+            zone.wildcard("x.tv.home.arpa", QueryType::A),
+            Some(vec![a("x.tv.home.arpa", "10.0.0.1")])
+        );
+        assert_eq!(zone.wildcard("tv.home.arpa", QueryType::AAAA), Some(vec![]));
+        // The closest encloser has no wildcard
+        assert_eq!(zone.wildcard("x.nas.home.arpa", QueryType::A), None);
+        assert_eq!(zone.wildcard("c.b.home.arpa", QueryType::A), None);
+    }
+}
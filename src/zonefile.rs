@@ -0,0 +1,295 @@
+// RFC 1035 master file
+// https://www.rfc-editor.org/rfc/rfc1035#section-5
+
+use crate::{
+    config::{Invalid, InvalidType, Parser},
+    zone::is_subdomain,
+    DEFAULT_TTL,
+};
+use std::{
+    path::{Path, PathBuf},
+    result,
+};
+use tokio::{fs, io::Result};
+use updns::DnsRecord;
+
+const CLASSES: [&str; 4] = ["IN", "CS", "CH", "HS"];
+
+pub async fn load<P: AsRef<Path>>(origin: &str, path: P) -> Result<(Vec<DnsRecord>, Vec<Invalid>)> {
+    let content = fs::read_to_string(&path).await?;
+    let (records, mut invalid) = parse(origin, &content);
+    for invalid in invalid.iter_mut() {
+        invalid.path = path.as_ref().to_path_buf();
+    }
+    Ok((records, invalid))
+}
+
+// Remove the comment and the parentheses, return the change of the parentheses depth
+// example.com. IN SOA ns hostmaster ( ; comment  ->  example.com. IN SOA ns hostmaster
+fn strip(line: &str) -> (String, isize) {
+    let mut text = String::with_capacity(line.len());
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut chars = line.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                text.push(ch);
+                if let Some(next) = chars.next() {
+                    text.push(next);
+                }
+            }
+            '"' => {
+                quoted = !quoted;
+                text.push(ch);
+            }
+            ';' if !quoted => break,
+            '(' if !quoted => {
+                depth += 1;
+                text.push(' ');
+            }
+            ')' if !quoted => {
+                depth -= 1;
+                text.push(' ');
+            }
+            _ => text.push(ch),
+        }
+    }
+
+    (text, depth)
+}
+
+// Names without a trailing dot are relative to the origin
+// @  or  www  or  www.example.com.  or  .
+fn absolute(name: &str, origin: &str) -> result::Result<String, InvalidType> {
+    match name {
+        "@" => Ok(origin.to_string()),
+        "." => Ok(String::new()),
+        _ if name.ends_with('.') => Parser::hostname(name),
+        _ if origin.is_empty() => Parser::hostname(name),
+        _ => Parser::hostname(&format!("{}.{}", name, origin)),
+    }
+}
+
+pub fn parse(origin: &str, content: &str) -> (Vec<DnsRecord>, Vec<Invalid>) {
+    let zone = origin.to_string();
+    let mut origin = origin.to_string();
+    let mut default_ttl = None;
+    let mut last_owner: Option<String> = None;
+    let mut last_ttl = None;
+
+    let mut records = Vec::new();
+    let mut invalid = Vec::new();
+
+    // A record may span multiple lines within parentheses
+    let mut entry = String::new();
+    let mut source = String::new();
+    let mut start = 0;
+    let mut depth = 0;
+
+    for (i, line) in content.lines().enumerate() {
+        let (text, change) = strip(line);
+        if depth == 0 {
+            start = i;
+            entry.clear();
+            source.clear();
+        }
+        entry.push_str(&text);
+        entry.push(' ');
+        source.push_str(line.trim());
+        source.push(' ');
+        depth += change;
+        if depth > 0 {
+            continue;
+        }
+        depth = 0;
+
+        // An empty owner repeats the previous one
+        let blank_owner = entry.starts_with(|c: char| c.is_ascii_whitespace());
+
+        macro_rules! invalid {
+            ($type: expr) => {{
+                invalid.push(Invalid {
+                    path: PathBuf::new(),
+                    line: start + 1,
+                    source: source.trim().to_string(),
+                    kind: $type,
+                });
+                continue;
+            }};
+        }
+
+        let mut fields = match Parser::fields(&entry) {
+            Ok(fields) => fields.into_iter(),
+            Err(kind) => invalid!(kind),
+        };
+
+        let first = match fields.next() {
+            Some(first) => first,
+            None => continue,
+        };
+
+        // Control entries
+        match first.as_str() {
+            "$ORIGIN" => {
+                match fields.next().map(|name| absolute(&name, &origin)) {
+                    Some(Ok(name)) => origin = name,
+                    _ => invalid!(InvalidType::Hostname),
+                }
+                continue;
+            }
+            "$TTL" => {
+                match fields.next().and_then(|ttl| Parser::ttl(&ttl)) {
+                    Some(ttl) => default_ttl = Some(ttl),
+                    None => invalid!(InvalidType::Timeout),
+                }
+                continue;
+            }
+            _ if first.starts_with('$') => invalid!(InvalidType::Other),
+            _ => {}
+        }
+
+        let mut fields = fields.collect::<Vec<String>>();
+        let owner = if blank_owner {
+            fields.insert(0, first);
+            match &last_owner {
+                Some(owner) => owner.clone(),
+                None => invalid!(InvalidType::Hostname),
+            }
+        } else {
+            match absolute(&first, &origin) {
+                Ok(owner) => owner,
+                Err(kind) => invalid!(kind),
+            }
+        };
+
+        // [ttl] [class] type  or  [class] [ttl] type
+        let mut ttl = None;
+        let mut i = 0;
+        while i < fields.len() {
+            if let Some(n) = Parser::ttl(&fields[i]) {
+                ttl = Some(n);
+            } else if !CLASSES.contains(&fields[i].to_ascii_uppercase().as_str()) {
+                break;
+            }
+            i += 1;
+        }
+        if i >= fields.len() {
+            invalid!(InvalidType::Record);
+        }
+
+        let host = |name: &str| absolute(name, &origin);
+        let mut record = match Parser::data(&fields[i], &fields[i + 1..], &host) {
+            Ok(record) => record,
+            Err(kind) => invalid!(kind),
+        };
+
+        if !is_subdomain(&owner, &zone) {
+            invalid!(InvalidType::Hostname);
+        }
+
+        let ttl = ttl.or(last_ttl).or(default_ttl).unwrap_or(DEFAULT_TTL);
+        record.set_domain(owner.clone());
+        record.set_ttl(ttl);
+        records.push(record);
+
+        last_owner = Some(owner);
+        if default_ttl.is_none() {
+            last_ttl = Some(ttl);
+        }
+    }
+
+    (records, invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn parse_zone_file() -> Result<()> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test_data")
+            .join("db.example.test");
+
+        let (records, invalid) = load("example.test", path).await?;
+        assert!(invalid.is_empty(), "{:?}", invalid);
+
+        assert_eq!(
+            records,
+            vec![
+                DnsRecord::SOA {
+                    domain: "example.test".to_string(),
+                    mname: "ns1.example.test".to_string(),
+                    rname: "hostmaster.example.test".to_string(),
+                    serial: 2024010101,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 604800,
+                    minimum: 300,
+                    ttl: 86400,
+                },
+                DnsRecord::NS {
+                    domain: "example.test".to_string(),
+                    host: "ns1.example.test".to_string(),
+                    ttl: 86400,
+                },
+                DnsRecord::MX {
+                    domain: "example.test".to_string(),
+                    priority: 10,
+                    host: "mail.example.test".to_string(),
+                    ttl: 86400,
+                },
+                DnsRecord::A {
+                    domain: "ns1.example.test".to_string(),
+                    addr: "10.0.0.1".parse().unwrap(),
+                    ttl: 86400,
+                },
+                DnsRecord::A {
+                    domain: "www.example.test".to_string(),
+                    addr: "10.0.0.2".parse().unwrap(),
+                    ttl: 300,
+                },
+                DnsRecord::AAAA {
+                    domain: "www.example.test".to_string(),
+                    addr: "fd00::2".parse().unwrap(),
+                    ttl: 86400,
+                },
+                DnsRecord::CNAME {
+                    domain: "mail.example.test".to_string(),
+                    host: "www.example.test".to_string(),
+                    ttl: 86400,
+                },
+                DnsRecord::TXT {
+                    domain: "example.test".to_string(),
+                    data: vec!["v=spf1 -all".to_string(), "; not a comment".to_string()],
+                    ttl: 86400,
+                },
+                DnsRecord::SRV {
+                    domain: "_sip._tcp.lab.example.test".to_string(),
+                    priority: 10,
+                    weight: 5,
+                    port: 5060,
+                    host: "sip.lab.example.test".to_string(),
+                    ttl: 86400,
+                },
+                DnsRecord::A {
+                    domain: "sip.lab.example.test".to_string(),
+                    addr: "10.0.1.5".parse().unwrap(),
+                    ttl: 86400,
+                },
+            ]
+        );
+
+        let (_, invalid) = parse(
+            "example.test",
+            "www IN A 10.0.0.300\nexample.com. A 10.0.0.1",
+        );
+        let lines: Vec<_> = invalid.iter().map(|invalid| invalid.line).collect();
+        assert_eq!(lines, vec![1, 2]);
+
+        Ok(())
+    }
+}